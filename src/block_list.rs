@@ -1,17 +1,85 @@
-use curl::easy::{Easy2, Handler, WriteError};
-use regex::Regex;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use chrono::NaiveDateTime;
+use curl::easy::{Easy2, Handler, List, WriteError};
+use flate2::read::GzDecoder;
+use ipnet::IpNet;
+use minisign_verify::{PublicKey, Signature};
+use rand::Rng;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use xz2::read::XzDecoder;
 
+use crate::dns_message;
 use crate::error::BlockListError;
+use crate::metrics;
+use crate::pause::{Pauses, Target};
+use crate::schedule::Schedule;
 
-struct Collector(Vec<u8>);
+// How often a list that has never loaded is tried again
+const PENDING_RETRY: Duration = Duration::from_secs(60);
+
+// Minisign signatures are a few hundred bytes, so anything much bigger isn't one
+const MAX_SIGNATURE_SIZE: usize = 4096;
+
+#[derive(Default)]
+struct Collector {
+    body: Vec<u8>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    cache_control: Option<String>,
+    expires: Option<String>,
+    max_size: Option<usize>,
+    too_large: bool,
+}
 
 impl Handler for Collector {
     fn write(&mut self, data: &[u8]) -> std::result::Result<usize, WriteError> {
-        self.0.extend_from_slice(data);
+        // Taking less than we were given makes curl give up on the download
+        if let Some(max_size) = self.max_size {
+            if self.body.len() + data.len() > max_size {
+                self.too_large = true;
+                return Ok(0);
+            }
+        }
+        self.body.extend_from_slice(data);
         Ok(data.len())
     }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(data);
+        if let Some(i) = line.find(':') {
+            let name = line[..i].trim().to_lowercase();
+            let value = line[i + 1..].trim().to_string();
+            match name.as_str() {
+                "etag" => self.etag = Some(value),
+                "last-modified" => self.last_modified = Some(value),
+                "cache-control" => self.cache_control = Some(value),
+                "expires" => self.expires = Some(value),
+                _ => (),
+            }
+        }
+        true
+    }
+}
+
+// The validators for a downloaded list, saved next to the on-disk copy so
+// the next download can be conditional
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct HttpValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// Along with the outcome, how long the server says the list stays fresh
+enum Download {
+    Modified(Vec<u8>, HttpValidators, Option<Duration>),
+    NotModified(Option<Duration>),
 }
 
 pub type Result = std::result::Result<(), BlockListError>;
@@ -20,12 +88,169 @@ pub type Result = std::result::Result<(), BlockListError>;
 pub enum BlockListKind {
     File,
     Http,
+    Inline,
 }
 
 #[derive(Clone, Debug)]
 pub enum BlockListFormat {
     Hosts,
     OnePerLine,
+    Adblock,
+    Dnsmasq,
+    Rpz,
+    Regex,
+    Cidr,
+}
+
+impl BlockListKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockListKind::File => "file",
+            BlockListKind::Http => "http",
+            BlockListKind::Inline => "inline",
+        }
+    }
+}
+
+impl BlockListFormat {
+    // The same names the formats have in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockListFormat::Hosts => "hosts",
+            BlockListFormat::OnePerLine => "one-per-line",
+            BlockListFormat::Adblock => "adblock",
+            BlockListFormat::Dnsmasq => "dnsmasq",
+            BlockListFormat::Rpz => "rpz",
+            BlockListFormat::Regex => "regex",
+            BlockListFormat::Cidr => "cidr",
+        }
+    }
+}
+
+// What to answer with when a query matches an entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Nxdomain,
+    Nodata,
+    LocalData(Vec<IpAddr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    // For regex entries this holds the pattern rather than a hostname
+    pub hostname: String,
+    pub regex: bool,
+    // Set for entries from IP block lists, which match addresses in answers
+    pub network: Option<IpNet>,
+    pub include_subdomains: bool,
+    pub exception: bool,
+    pub important: bool,
+    pub action: Action,
+    // The query types the entry applies to, empty meaning all of them, and
+    // the ones it never applies to
+    pub qtypes: Vec<u16>,
+    pub except_qtypes: Vec<u16>,
+}
+
+impl Entry {
+    fn exact(hostname: String) -> Entry {
+        Entry::domain(hostname, false)
+    }
+
+    pub fn domain(hostname: String, include_subdomains: bool) -> Entry {
+        Entry {
+            hostname,
+            regex: false,
+            network: None,
+            include_subdomains,
+            exception: false,
+            important: false,
+            action: Action::Nxdomain,
+            qtypes: Vec::new(),
+            except_qtypes: Vec::new(),
+        }
+    }
+
+    // Limits the entry to some query types. Blocking only some types of a
+    // name answers NODATA instead of NXDOMAIN, since NXDOMAIN would say the
+    // name doesn't exist for any type.
+    pub fn for_qtypes(self, qtypes: Vec<u16>, except_qtypes: Vec<u16>) -> Entry {
+        let action = match self.action {
+            Action::Nxdomain if !qtypes.is_empty() || !except_qtypes.is_empty() => Action::Nodata,
+            action => action,
+        };
+
+        Entry {
+            action,
+            qtypes,
+            except_qtypes,
+            ..self
+        }
+    }
+
+    fn applies_to(&self, qtype: u16) -> bool {
+        (self.qtypes.is_empty() || self.qtypes.contains(&qtype))
+            && !self.except_qtypes.contains(&qtype)
+    }
+
+    pub fn regex(pattern: String) -> Entry {
+        Entry {
+            regex: true,
+            ..Entry::exact(pattern)
+        }
+    }
+
+    pub fn network(network: IpNet) -> Entry {
+        Entry {
+            network: Some(network),
+            ..Entry::exact(network.to_string())
+        }
+    }
+
+    // Regex entries are matched all at once through BlockLists' RegexSet,
+    // and network entries only ever match addresses
+    fn matches(&self, hostname: &str) -> bool {
+        if self.regex || self.network.is_some() {
+            return false;
+        }
+
+        // RPZ style '*.example.com' covers the subdomains but not the
+        // domain itself
+        if let Some(parent) = self.hostname.strip_prefix("*.") {
+            return is_subdomain(hostname, parent);
+        }
+
+        if self.hostname == hostname {
+            return true;
+        }
+
+        self.include_subdomains && is_subdomain(hostname, &self.hostname)
+    }
+}
+
+// Settings that can be given to each list individually
+#[derive(Clone, Debug, Default)]
+pub struct BlockListOptions {
+    // Lists with a name are only used by client groups that ask for them,
+    // lists without one are the default lists everyone else gets
+    pub name: Option<String>,
+    // Minutes between refreshes, overriding the default for all lists
+    pub refresh_after: Option<u64>,
+    // Hex SHA-256 the list's contents must have
+    pub sha256: Option<String>,
+    // Minisign public key the list must be signed with, and where to find
+    // the signature if it isn't next to the list with '.minisig' on the end
+    pub public_key: Option<String>,
+    pub signature_url: Option<String>,
+    // Lists bigger than these are rejected
+    pub max_size: Option<usize>,
+    pub max_entries: Option<usize>,
+    // Query types every entry in the list applies to (or doesn't), unless
+    // the entry says otherwise
+    pub qtypes: Vec<u16>,
+    pub except_qtypes: Vec<u16>,
+    // When the list is in force, checked each time it's looked at
+    pub schedule: Schedule,
 }
 
 #[derive(Clone, Debug)]
@@ -34,102 +259,379 @@ pub struct BlockList {
     pub format: BlockListFormat,
     pub path: Option<String>,
     pub url: Option<String>,
-    pub entries: Vec<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub options: BlockListOptions,
+    pub next_refresh: Option<Instant>,
+    // When the list was last loaded or found to be up to date
+    pub refreshed_at: Option<SystemTime>,
+    pub entries: Vec<Entry>,
+    indexes: Indexes,
+    // Whether the entries are the copy on disk, used because the list
+    // couldn't be downloaded. Such lists are retried like pending ones.
+    from_disk: bool,
+}
+
+// Where a list's regex and network entries are, so they can be matched
+// without going through every entry. The list's regex entries are compiled
+// into a RegexSet of their own, which checks they're valid; queries use
+// the set combined from every list (see RegexIndex) when it can be built.
+#[derive(Clone, Debug)]
+struct Indexes {
+    regex_set: RegexSet,
+    // The index of the entry each pattern in the regex set came from
+    regex_entries: Vec<usize>,
+    network_entries: Vec<usize>,
+}
+
+impl Indexes {
+    // Built before a list is added, so a list whose patterns won't compile
+    // is never used
+    fn build(entries: &[Entry]) -> std::result::Result<Indexes, BlockListError> {
+        let mut patterns = Vec::new();
+        let mut regex_entries = Vec::new();
+        let mut network_entries = Vec::new();
+
+        for (entry_index, entry) in entries.iter().enumerate() {
+            if entry.regex {
+                patterns.push(entry.hostname.as_str());
+                regex_entries.push(entry_index);
+            } else if entry.network.is_some() {
+                network_entries.push(entry_index);
+            }
+        }
+
+        Ok(Indexes {
+            regex_set: RegexSet::new(patterns)?,
+            regex_entries,
+            network_entries,
+        })
+    }
+}
+
+impl Default for Indexes {
+    fn default() -> Indexes {
+        Indexes {
+            regex_set: RegexSet::empty(),
+            regex_entries: Vec::new(),
+            network_entries: Vec::new(),
+        }
+    }
+}
+
+// Every list's regex entries compiled together, so a query is matched
+// against the patterns of all the lists in one pass rather than one per
+// list (each scheduled [[block_rule]] is a list of its own). Built the
+// first time it's needed after the lists change.
+#[derive(Clone, Debug)]
+struct RegexIndex {
+    // Each set along with the list and entry every pattern came from
+    sets: Vec<(RegexSet, Vec<(usize, usize)>)>,
+}
+
+impl RegexIndex {
+    fn build(lists: &[BlockList]) -> RegexIndex {
+        let locations: Vec<(usize, usize)> = lists
+            .iter()
+            .enumerate()
+            .flat_map(|(list_index, list)| {
+                list.indexes
+                    .regex_entries
+                    .iter()
+                    .map(move |entry_index| (list_index, *entry_index))
+            })
+            .collect();
+        if locations.is_empty() {
+            return RegexIndex { sets: Vec::new() };
+        }
+
+        let patterns = locations.iter().map(|(list_index, entry_index)| {
+            lists[*list_index].entries[*entry_index].hostname.as_str()
+        });
+        match RegexSet::new(patterns) {
+            Ok(set) => RegexIndex {
+                sets: vec![(set, locations)],
+            },
+            Err(e) => {
+                // Each list's own set compiled when it was added, so those
+                // can still be used, just with a pass for each
+                warn!(
+                    "Couldn't combine the regex rules, matching each list separately: {}",
+                    e
+                );
+                let sets = lists
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, list)| !list.indexes.regex_entries.is_empty())
+                    .map(|(list_index, list)| {
+                        let locations = list
+                            .indexes
+                            .regex_entries
+                            .iter()
+                            .map(|entry_index| (list_index, *entry_index))
+                            .collect();
+                        (list.indexes.regex_set.clone(), locations)
+                    })
+                    .collect();
+                RegexIndex { sets }
+            }
+        }
+    }
+}
+
+impl BlockList {
+    // How the list is referred to when pausing it, e.g. from the control API
+    pub fn id(&self) -> &str {
+        self.options
+            .name
+            .as_deref()
+            .or(self.url.as_deref())
+            .or(self.path.as_deref())
+            .unwrap_or("block_rule")
+    }
+}
+
+// The lists in force for a query (see BlockLists::select)
+#[derive(Debug, Default)]
+pub struct Selection {
+    selected: Vec<bool>,
+}
+
+impl Selection {
+    fn includes(&self, list_index: usize) -> bool {
+        self.selected.get(list_index).copied().unwrap_or(false)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct BlockLists {
     pub lists: Vec<BlockList>,
+    cache_dir: Option<PathBuf>,
+    refresh_after: Option<u64>,
+    refresh_jitter: u64,
+    // Shared between every copy, so pauses carry over when lists refresh
+    pauses: Arc<Pauses>,
+    // Cleared by anything that changes the lists
+    regex_index: OnceLock<RegexIndex>,
 }
 
 impl BlockLists {
     pub fn new() -> BlockLists {
         let lists = Vec::new();
-        BlockLists { lists }
+        BlockLists {
+            lists,
+            cache_dir: None,
+            refresh_after: None,
+            refresh_jitter: 0,
+            pauses: Arc::new(Pauses::new()),
+            regex_index: OnceLock::new(),
+        }
+    }
+
+    pub fn pauses(&self) -> Arc<Pauses> {
+        Arc::clone(&self.pauses)
+    }
+
+    // Whether all blocking has been paused
+    pub fn is_paused(&self) -> bool {
+        self.pauses.is_paused(&Target::All)
+    }
+
+    // Only refreshes the lists whose refresh time has come round
+    pub fn reload_due_lists(&mut self) -> Result {
+        let now = Instant::now();
+        self.reload(|list| list.next_refresh.is_some_and(|next| next <= now))
+    }
+
+    // When the next list is due to be refreshed, if any ever will be
+    pub fn next_refresh(&self) -> Option<Instant> {
+        self.lists.iter().filter_map(|list| list.next_refresh).min()
+    }
+
+    // Refreshes every list now whether it's due or not, or just the list
+    // with the id
+    pub fn refresh(&mut self, id: Option<&str>) -> Result {
+        self.reload(|list| id.is_none_or(|id| list.id() == id))
+    }
+
+    // Whether a list of names from a file or URL has loaded yet. Rules from
+    // the config file and IP lists don't count. With no such lists at all
+    // there's nothing to wait for.
+    pub fn is_loaded(&self) -> bool {
+        let named: Vec<&BlockList> = self
+            .lists
+            .iter()
+            .filter(|list| {
+                !matches!(list.kind, BlockListKind::Inline)
+                    && !matches!(list.format, BlockListFormat::Cidr)
+            })
+            .collect();
+        named.is_empty() || named.iter().any(|list| !list.entries.is_empty())
+    }
+
+    // Keeps a list that couldn't be loaded around with no entries, so it's
+    // retried in the background until it loads
+    pub fn add_pending(
+        &mut self,
+        kind: BlockListKind,
+        location: &str,
+        format: &BlockListFormat,
+        options: &BlockListOptions,
+    ) {
+        self.regex_index = OnceLock::new();
+        let (path, url) = match kind {
+            BlockListKind::Http => (None, Some(location.to_string())),
+            _ => (Some(location.to_string()), None),
+        };
+
+        self.lists.push(BlockList {
+            kind,
+            format: format.clone(),
+            path,
+            url,
+            etag: None,
+            last_modified: None,
+            options: options.clone(),
+            next_refresh: Some(Instant::now() + PENDING_RETRY),
+            refreshed_at: None,
+            entries: Vec::new(),
+            indexes: Indexes::default(),
+            from_disk: false,
+        });
     }
 
-    pub fn reload_lists(&mut self) -> Result {
-        let old_lists = self.lists.clone();
-        self.lists.truncate(0);
+    fn reload<F: Fn(&BlockList) -> bool>(&mut self, should_reload: F) -> Result {
+        self.regex_index = OnceLock::new();
+        let old_lists = std::mem::take(&mut self.lists);
+        let mut attempted = 0;
         let mut updated = 0;
 
         // We're going to use unwrap here since it makes the code cleaner and there has already
         // been validation to make sure they should be a 'Some' value.
         for list in &old_lists {
-            match &list.kind {
+            if !should_reload(list) {
+                self.lists.push(list.clone());
+                continue;
+            }
+
+            let result = match &list.kind {
                 BlockListKind::File => {
-                    match self.add_file(list.path.as_ref().unwrap(), &list.format) {
-                        Ok(_) => {
-                            debug!("Refreshed block list at {}", list.path.as_ref().unwrap());
-                            updated += 1;
-                        }
-                        Err(e) => {
-                            warn!(
-                                "Could not refresh block list at {} - Reason: {}",
-                                list.path.as_ref().unwrap(),
-                                e
-                            );
-                            // Add old block list back into the list
-                            self.lists.push(list.clone());
-                            continue;
-                        }
-                    }
+                    self.add_file(list.path.as_ref().unwrap(), &list.format, &list.options)
+                }
+                BlockListKind::Inline => {
+                    // Rules from the config file don't change until restart
+                    self.lists.push(list.clone());
+                    continue;
                 }
                 BlockListKind::Http => {
-                    match self.add_http(list.url.as_ref().unwrap(), &list.format) {
-                        Ok(_) => {
-                            debug!("Refreshed block list at {}", list.url.as_ref().unwrap());
-                            updated += 1;
-                        }
-                        Err(e) => {
-                            warn!(
-                                "Could not refresh block list at {} - Reason: {}",
-                                list.url.as_ref().unwrap(),
-                                e
-                            );
-                            // Add old block list back into the list
-                            self.lists.push(list.clone());
-                            continue;
-                        }
-                    }
+                    let url = list.url.as_ref().unwrap();
+                    self.fetch_http(url, &list.format, &list.options, Some(list))
                 }
             };
+            attempted += 1;
+
+            let location = list.path.as_ref().or(list.url.as_ref()).unwrap();
+            metrics::list_refresh(list.id(), result.is_ok());
+            match result {
+                Ok(_) => {
+                    debug!("Refreshed block list at {}", location);
+                    updated += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not refresh block list at {} - Reason: {}",
+                        location, e
+                    );
+                    // Add old block list back into the list, and try again
+                    // next time round rather than on every check. Lists that
+                    // never loaded, or are only the copy on disk, keep being
+                    // retried until they download.
+                    let mut old = list.clone();
+                    old.next_refresh = if old.entries.is_empty() || old.from_disk {
+                        Some(Instant::now() + PENDING_RETRY)
+                    } else {
+                        self.schedule(&old.options, None)
+                    };
+                    self.lists.push(old);
+                }
+            }
         }
 
         std::mem::drop(old_lists);
 
-        if updated == 0 {
-            return Err(BlockListError::no_entries());
+        if attempted > 0 && updated == 0 {
+            Err(BlockListError::no_entries())
         } else {
-            return Ok(());
+            Ok(())
         }
     }
 
-    pub fn add_file(&mut self, path: &String, format: &BlockListFormat) -> Result {
-        let mut entries: Vec<String> = Vec::new();
-
-        let file = File::open(path)?;
+    // Sets how often (in minutes) lists are refreshed unless they say
+    // otherwise, and up to how many extra minutes to wait at random so a
+    // lot of proxies don't all hit a list's host at once
+    pub fn set_refresh(&mut self, refresh_after: Option<u64>, refresh_jitter: Option<u64>) {
+        self.refresh_after = refresh_after;
+        self.refresh_jitter = refresh_jitter.unwrap_or(0);
+    }
 
-        let reader = BufReader::new(file);
+    // Works out when a list should next be refreshed. The server saying the
+    // list stays fresh for longer than we'd otherwise wait pushes it back.
+    fn schedule(&self, options: &BlockListOptions, fresh_for: Option<Duration>) -> Option<Instant> {
+        let minutes = match options.refresh_after.or(self.refresh_after) {
+            Some(0) | None => return None,
+            Some(m) => m,
+        };
 
-        for (_index, line_res) in reader.lines().enumerate() {
-            if let Ok(line) = line_res {
-                if let Some(processed_line) = process_line(&line, &format) {
-                    entries.push(processed_line);
-                }
-            }
+        let mut wait = Duration::from_secs(minutes * 60);
+        if let Some(fresh_for) = fresh_for {
+            wait = wait.max(fresh_for);
         }
-
-        if entries.len() == 0 {
-            return Err(BlockListError::no_entries());
+        if self.refresh_jitter > 0 {
+            let jitter = rand::thread_rng().gen_range(0, self.refresh_jitter * 60 + 1);
+            wait += Duration::from_secs(jitter);
         }
 
+        Some(Instant::now() + wait)
+    }
+
+    pub fn add_file(
+        &mut self,
+        path: &String,
+        format: &BlockListFormat,
+        options: &BlockListOptions,
+    ) -> Result {
+        self.regex_index = OnceLock::new();
+        let contents = fs::read(path)?;
+        let signature = match &options.public_key {
+            Some(_) => Some(match &options.signature_url {
+                Some(url) => fetch(url, MAX_SIGNATURE_SIZE)?,
+                None => fs::read(format!("{}.minisig", path))?,
+            }),
+            None => None,
+        };
+        verify(&contents, signature.as_deref(), options)?;
+
+        let contents = decompress(contents, options.max_size)?;
+        let result = String::from_utf8_lossy(&contents);
+
+        let lines = result.lines().map(|line| line.to_string());
+        let entries = apply_qtypes(process_lines(lines, format), options);
+
+        check_entries(&entries, options)?;
+
         let list = BlockList {
             kind: BlockListKind::File,
             format: format.clone(),
             path: Some(path.clone()),
             url: None,
-            entries: entries,
+            etag: None,
+            last_modified: None,
+            options: options.clone(),
+            next_refresh: self.schedule(options, None),
+            refreshed_at: Some(SystemTime::now()),
+            indexes: Indexes::build(&entries)?,
+            from_disk: false,
+            entries,
         };
 
         self.lists.push(list);
@@ -137,116 +639,1078 @@ impl BlockLists {
         Ok(())
     }
 
-    pub fn add_http(&mut self, url: &String, format: &BlockListFormat) -> Result {
-        let mut easy = Easy2::new(Collector(Vec::new()));
-        easy.get(true)?;
-        easy.url(url.as_str())?;
-        easy.perform()?;
+    // Keep a copy of every downloaded list in the directory, to fall back on
+    // if a list can't be downloaded at startup
+    pub fn set_cache_dir(&mut self, dir: &String) {
+        self.cache_dir = Some(PathBuf::from(dir));
+    }
 
-        if easy.response_code()? != 200 {
-            return Err(BlockListError::http_not_ok());
-        }
+    pub fn add_http(
+        &mut self,
+        url: &String,
+        format: &BlockListFormat,
+        options: &BlockListOptions,
+    ) -> Result {
+        self.regex_index = OnceLock::new();
+        self.fetch_http(url, format, options, None)
+    }
 
-        let contents = easy.get_ref();
-        let result = String::from_utf8_lossy(&contents.0);
+    fn fetch_http(
+        &mut self,
+        url: &str,
+        format: &BlockListFormat,
+        options: &BlockListOptions,
+        previous: Option<&BlockList>,
+    ) -> Result {
+        // The copy on disk is only needed when there's no list already, and
+        // gets the same checks as a download since anyone could have written it
+        let cached = match previous {
+            Some(_) => None,
+            None => self.read_cached(url).filter(|(body, _, signature)| {
+                match verify(body, signature.as_deref(), options) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!("Ignoring the copy on disk of block list {}: {}", url, e);
+                        false
+                    }
+                }
+            }),
+        };
 
-        let mut entries = Vec::new();
+        // Make the download conditional on whatever copy we already have
+        let validators = match (previous, &cached) {
+            (Some(list), _) => HttpValidators {
+                etag: list.etag.clone(),
+                last_modified: list.last_modified.clone(),
+            },
+            (None, Some((_, validators, _))) => validators.clone(),
+            (None, None) => HttpValidators::default(),
+        };
 
-        for line in result.lines() {
-            if let Some(processed_line) = process_line(&line.to_string(), &format) {
-                entries.push(processed_line);
+        // A list that fails verification is treated like one that couldn't
+        // be downloaded, so whatever we had before is kept
+        let downloaded = download(url, &validators, options.max_size).and_then(|d| {
+            let mut signature = None;
+            if let Download::Modified(body, _, _) = &d {
+                signature = match &options.public_key {
+                    Some(_) => match &options.signature_url {
+                        Some(signature_url) => Some(fetch(signature_url, MAX_SIGNATURE_SIZE)?),
+                        None => Some(fetch(&format!("{}.minisig", url), MAX_SIGNATURE_SIZE)?),
+                    },
+                    None => None,
+                };
+                verify(body, signature.as_deref(), options)?;
             }
-        }
+            Ok((d, signature))
+        });
 
-        if entries.len() == 0 {
-            return Err(BlockListError::no_entries());
+        match downloaded {
+            Ok((Download::Modified(body, validators, fresh_for), signature)) => {
+                let next_refresh = self.schedule(options, fresh_for);
+                self.push_http(
+                    url,
+                    format,
+                    options,
+                    next_refresh,
+                    &body,
+                    validators.clone(),
+                )?;
+                self.write_cached(url, &body, signature.as_deref(), &validators);
+                Ok(())
+            }
+            Ok((Download::NotModified(fresh_for), _)) => {
+                debug!("Block list at {} hasn't changed", url);
+                let next_refresh = self.schedule(options, fresh_for);
+                if let Some(list) = previous {
+                    let mut list = list.clone();
+                    list.next_refresh = next_refresh;
+                    list.refreshed_at = Some(SystemTime::now());
+                    list.from_disk = false;
+                    self.lists.push(list);
+                    return Ok(());
+                }
+                match cached {
+                    Some((body, validators, _)) => {
+                        self.push_http(url, format, options, next_refresh, &body, validators)
+                    }
+                    None => Err(BlockListError::http_not_ok()),
+                }
+            }
+            Err(e) => match (previous, cached) {
+                (None, Some((body, validators, _))) => {
+                    warn!(
+                        "Couldn't download block list at {} ({}), using the copy on disk",
+                        url, e
+                    );
+                    // Keep trying to download it, rather than running on the
+                    // copy until the next scheduled refresh (if there is one)
+                    let next_refresh = Some(Instant::now() + PENDING_RETRY);
+                    self.push_http(url, format, options, next_refresh, &body, validators)?;
+                    if let Some(list) = self.lists.last_mut() {
+                        list.from_disk = true;
+                    }
+                    Ok(())
+                }
+                _ => Err(e),
+            },
         }
+    }
+
+    fn push_http(
+        &mut self,
+        url: &str,
+        format: &BlockListFormat,
+        options: &BlockListOptions,
+        next_refresh: Option<Instant>,
+        body: &[u8],
+        validators: HttpValidators,
+    ) -> Result {
+        // Compressed lists are kept compressed on disk, and only expanded
+        // here once they've been verified
+        let body = decompress(body.to_vec(), options.max_size)?;
+        let result = String::from_utf8_lossy(&body);
+
+        let lines = result.lines().map(|line| line.to_string());
+        let entries = apply_qtypes(process_lines(lines, format), options);
+
+        check_entries(&entries, options)?;
 
         let list = BlockList {
             kind: BlockListKind::Http,
             format: format.clone(),
             path: None,
-            url: Some(url.clone()),
-            entries: entries,
+            url: Some(url.to_string()),
+            etag: validators.etag,
+            last_modified: validators.last_modified,
+            options: options.clone(),
+            next_refresh,
+            refreshed_at: Some(SystemTime::now()),
+            indexes: Indexes::build(&entries)?,
+            from_disk: false,
+            entries,
+        };
+
+        self.lists.push(list);
+        Ok(())
+    }
+
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+
+        Some(dir.join(cache_file_name(url)))
+    }
+
+    // The copy of a list saved on disk, along with its validators and
+    // signature if it had them
+    fn read_cached(&self, url: &str) -> Option<(Vec<u8>, HttpValidators, Option<Vec<u8>>)> {
+        let path = self.cache_path(url)?;
+        let body = fs::read(&path).ok()?;
+
+        let validators = fs::read_to_string(path.with_extension("meta"))
+            .ok()
+            .and_then(|m| toml::from_str(&m).ok())
+            .unwrap_or_default();
+        let signature = fs::read(path.with_extension("minisig")).ok();
+
+        Some((body, validators, signature))
+    }
+
+    fn write_cached(
+        &self,
+        url: &str,
+        body: &[u8],
+        signature: Option<&[u8]>,
+        validators: &HttpValidators,
+    ) {
+        let path = match self.cache_path(url) {
+            Some(p) => p,
+            None => return,
+        };
+
+        let res = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, body))
+            .and_then(|_| {
+                let meta = toml::to_string(validators).unwrap_or_default();
+                fs::write(path.with_extension("meta"), meta)
+            })
+            .and_then(|_| match signature {
+                Some(signature) => fs::write(path.with_extension("minisig"), signature),
+                None => Ok(()),
+            });
+
+        if let Err(e) = res {
+            warn!("Couldn't save a copy of block list {}: {}", url, e);
+        }
+    }
+
+    // Adds rules that were written directly into the config file. Rules
+    // with their own schedule are added as a list of their own.
+    pub fn add_rules(&mut self, entries: Vec<Entry>, options: &BlockListOptions) -> Result {
+        self.regex_index = OnceLock::new();
+        if entries.is_empty() {
+            return Err(BlockListError::no_entries());
+        }
+
+        let mut normalized = Vec::new();
+        for entry in entries {
+            let hostname = entry.hostname.clone();
+            match normalize_entry(entry) {
+                Some(entry) => normalized.push(entry),
+                None => return Err(BlockListError::invalid_hostname(&hostname)),
+            }
+        }
+        let entries = normalized;
+
+        let list = BlockList {
+            kind: BlockListKind::Inline,
+            format: BlockListFormat::OnePerLine,
+            path: None,
+            url: None,
+            etag: None,
+            last_modified: None,
+            options: options.clone(),
+            next_refresh: None,
+            refreshed_at: Some(SystemTime::now()),
+            indexes: Indexes::build(&entries)?,
+            from_disk: false,
+            entries,
         };
 
         self.lists.push(list);
         Ok(())
     }
 
-    pub fn is_blocked(&self, hostname: &String) -> bool {
-        for list in &self.lists {
-            for entry in &list.entries {
-                if entry == hostname {
-                    return true;
+    // Works out which lists a query uses: the named lists, where None and
+    // 'default' mean the lists without a name, less any that are paused or
+    // outside their schedule at the time given (in local time). Done once
+    // per query rather than for every entry looked at.
+    pub fn select(&self, lists: Option<&[String]>, at: NaiveDateTime) -> Selection {
+        let paused = self.pauses.paused();
+        let all_paused = paused.iter().any(|(target, _)| *target == Target::All);
+        let selected = self
+            .lists
+            .iter()
+            .map(|list| {
+                let options = &list.options;
+                if all_paused || !options.schedule.is_active(at) {
+                    return false;
+                }
+                if paused.iter().any(|(target, _)| target.is_list(list.id())) {
+                    return false;
+                }
+
+                match (&options.name, lists) {
+                    (None, None) => true,
+                    (Some(_), None) => false,
+                    (None, Some(names)) => names.iter().any(|n| n == "default"),
+                    (Some(name), Some(names)) => names.contains(name),
                 }
+            })
+            .collect();
+
+        Selection { selected }
+    }
+
+    // Returns the list and entry that block a query for the hostname, or
+    // None if it shouldn't be blocked. Only the selected lists are used.
+    pub fn explain(
+        &self,
+        hostname: &str,
+        qtype: u16,
+        selection: &Selection,
+    ) -> Option<(&BlockList, &Entry)> {
+        self.find(hostname, qtype, selection)
+            .map(|(list_index, entry)| (&self.lists[list_index], entry))
+    }
+
+    fn find(&self, hostname: &str, qtype: u16, selection: &Selection) -> Option<(usize, &Entry)> {
+        // Queries can come in any case (e.g. with 0x20 randomisation)
+        let hostname = match normalize_hostname(hostname) {
+            Some(h) => h,
+            None => hostname.to_ascii_lowercase(),
+        };
+        let hostname = &hostname;
+        let mut blocked = None;
+        let mut excepted = false;
+
+        let literal_matches = self
+            .lists
+            .iter()
+            .enumerate()
+            .filter(|(list_index, _)| selection.includes(*list_index))
+            .flat_map(|(list_index, list)| list.entries.iter().map(move |e| (list_index, e)))
+            .filter(|(_, e)| e.matches(hostname) && e.applies_to(qtype));
+
+        let regex_index = self
+            .regex_index
+            .get_or_init(|| RegexIndex::build(&self.lists));
+        let regex_matches = regex_index
+            .sets
+            .iter()
+            .flat_map(|(set, locations)| {
+                set.matches(hostname).into_iter().map(move |i| locations[i])
+            })
+            .filter(|(list_index, _)| selection.includes(*list_index))
+            .map(|(list_index, entry_index)| {
+                (list_index, &self.lists[list_index].entries[entry_index])
+            })
+            .filter(|(_, entry)| entry.applies_to(qtype))
+            .inspect(|(_, entry)| debug!("{} matched regex rule '{}'", hostname, entry.hostname))
+            .collect::<Vec<(usize, &Entry)>>();
+
+        for (list_index, entry) in literal_matches.chain(regex_matches) {
+            // '$important' blocks win over everything, including exceptions
+            if entry.important && !entry.exception {
+                return Some((list_index, entry));
+            }
+
+            if entry.exception {
+                excepted = true;
+            } else if blocked.is_none() {
+                blocked = Some((list_index, entry));
             }
         }
 
-        false
+        if excepted {
+            return None;
+        }
+
+        blocked
     }
-}
 
-fn process_line(line: &String, format: &BlockListFormat) -> Option<String> {
-    let no_comments = match strip_comments(line) {
-        Some(s) => s,
-        None => return None,
-    };
+    // Checks where an upstream answer leads, so trackers hiding behind a
+    // first-party name (e.g. 'metrics.shop.com CNAME tracker.adtech.net')
+    // are still blocked. Returns the name that matched along with the list
+    // and action.
+    pub fn lookup_answer(
+        &self,
+        response: &[u8],
+        qtype: u16,
+        selection: &Selection,
+    ) -> Option<(String, &BlockList, &Action)> {
+        let targets = dns_message::cname_targets(response).ok()?;
 
-    if let BlockListFormat::Hosts = format {
-        if let Some(host) = extract_hostname(&no_comments) {
-            return Some(host);
-        } else {
+        for target in targets {
+            if let Some((list, entry)) = self.explain(&target, qtype, selection) {
+                return Some((target, list, &entry.action));
+            }
+        }
+
+        None
+    }
+
+    // Checks the addresses in an upstream answer against the IP block lists,
+    // returning the first blocked address along with the list and action
+    pub fn lookup_addresses(
+        &self,
+        response: &[u8],
+        selection: &Selection,
+    ) -> Option<(IpAddr, &BlockList, &Action)> {
+        let ip_lists: Vec<&BlockList> = self
+            .lists
+            .iter()
+            .enumerate()
+            .filter(|(list_index, list)| {
+                !list.indexes.network_entries.is_empty() && selection.includes(*list_index)
+            })
+            .map(|(_, list)| list)
+            .collect();
+        if ip_lists.is_empty() {
             return None;
         }
-    } else if let BlockListFormat::OnePerLine = format {
-        return Some(no_comments);
-    } else {
-        return None;
+
+        let addresses = dns_message::answer_addresses(response).ok()?;
+        for address in addresses {
+            for list in &ip_lists {
+                for entry_index in &list.indexes.network_entries {
+                    let entry = &list.entries[*entry_index];
+                    if entry.network.is_some_and(|n| n.contains(&address)) {
+                        return Some((address, list, &entry.action));
+                    }
+                }
+            }
+        }
+
+        None
     }
 }
 
-fn strip_comments(line: &String) -> Option<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"((^|\s+)#.+)"#).unwrap();
+fn download(
+    url: &str,
+    validators: &HttpValidators,
+    max_size: Option<usize>,
+) -> std::result::Result<Download, BlockListError> {
+    let mut easy = Easy2::new(Collector {
+        max_size,
+        ..Collector::default()
+    });
+    easy.get(true)?;
+    easy.url(url)?;
+    // Let curl ask for and undo any Content-Encoding it knows about
+    easy.accept_encoding("")?;
+
+    let mut headers = List::new();
+    if let Some(etag) = &validators.etag {
+        headers.append(&format!("If-None-Match: {}", etag))?;
     }
-    let no_comments = RE.replace(line, "").to_mut().to_string();
+    if let Some(last_modified) = &validators.last_modified {
+        headers.append(&format!("If-Modified-Since: {}", last_modified))?;
+    }
+    easy.http_headers(headers)?;
 
-    if no_comments == "" {
-        return None;
+    if let Err(e) = easy.perform() {
+        if easy.get_ref().too_large {
+            return Err(BlockListError::verification_failed("list is over max_size"));
+        }
+        return Err(e.into());
     }
 
-    Some(no_comments)
-}
+    let code = easy.response_code()?;
+    let collector = easy.get_mut();
+    let fresh_for = freshness(&collector.cache_control, &collector.expires);
 
-fn extract_hostname(line: &String) -> Option<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"^.+\s+"#).unwrap();
+    match code {
+        200 => (),
+        304 => return Ok(Download::NotModified(fresh_for)),
+        _ => return Err(BlockListError::http_not_ok()),
     }
 
-    let hostname = RE.replace(line, "").to_mut().to_string();
-    if hostname == "" {
-        return None;
-    }
+    let validators = HttpValidators {
+        etag: collector.etag.take(),
+        last_modified: collector.last_modified.take(),
+    };
+    let body = std::mem::take(&mut collector.body);
 
-    Some(hostname)
+    Ok(Download::Modified(body, validators, fresh_for))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn strip_comments_works() {
-        let line1 = "# A comment here".to_string();
-        let line2 = "Something here # but now a comment".to_string();
-        let line2_correct = "Something here".to_string();
+// Expands gzip, zstd and xz compressed lists, going by their magic numbers
+// rather than file names since lists get served and saved under all sorts.
+// max_size applies to the expanded list too, so a small download can't
+// blow up to fill memory.
+fn decompress(
+    body: Vec<u8>,
+    max_size: Option<usize>,
+) -> std::result::Result<Vec<u8>, BlockListError> {
+    let reader: Box<dyn Read> = if body.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(&body[..]))
+    } else if body.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::new(&body[..])?)
+    } else if body.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Box::new(XzDecoder::new(&body[..]))
+    } else {
+        return Ok(body);
+    };
 
-        let line1_res = strip_comments(&line1);
-        assert!(line1_res.is_none());
+    let limit = max_size.map_or(u64::MAX, |max_size| max_size as u64 + 1);
+    let mut expanded = Vec::new();
+    reader.take(limit).read_to_end(&mut expanded)?;
 
-        let line2_res = strip_comments(&line2).unwrap();
-        assert_eq!(line2_correct, line2_res);
+    if let Some(max_size) = max_size {
+        if expanded.len() > max_size {
+            return Err(BlockListError::verification_failed("list is over max_size"));
+        }
+    }
+
+    Ok(expanded)
+}
+
+// Downloads something small that goes along with a list, like a signature
+fn fetch(url: &str, max_size: usize) -> std::result::Result<Vec<u8>, BlockListError> {
+    match download(url, &HttpValidators::default(), Some(max_size))? {
+        Download::Modified(body, _, _) => Ok(body),
+        Download::NotModified(_) => Err(BlockListError::http_not_ok()),
+    }
+}
+
+// Checks a list's contents against the size limit, checksum and signature
+// it was configured with before anything in it is used
+fn verify(body: &[u8], signature: Option<&[u8]>, options: &BlockListOptions) -> Result {
+    if let Some(max_size) = options.max_size {
+        if body.len() > max_size {
+            return Err(BlockListError::verification_failed("list is over max_size"));
+        }
+    }
+
+    if let Some(expected) = &options.sha256 {
+        let digest = Sha256::digest(body);
+        let actual: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(BlockListError::verification_failed("SHA-256 doesn't match"));
+        }
+    }
+
+    if let Some(public_key) = &options.public_key {
+        let public_key = match PublicKey::from_base64(public_key.trim()) {
+            Ok(k) => k,
+            Err(_) => return Err(BlockListError::verification_failed("invalid public key")),
+        };
+        let signature = signature.map(String::from_utf8_lossy).unwrap_or_default();
+        let signature = match Signature::decode(&signature) {
+            Ok(s) => s,
+            Err(_) => return Err(BlockListError::verification_failed("invalid signature")),
+        };
+        if public_key.verify(body, &signature, false).is_err() {
+            return Err(BlockListError::verification_failed("bad signature"));
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_qtypes(entries: Vec<Entry>, options: &BlockListOptions) -> Vec<Entry> {
+    if options.qtypes.is_empty() && options.except_qtypes.is_empty() {
+        return entries;
+    }
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            if entry.qtypes.is_empty() && entry.except_qtypes.is_empty() {
+                entry.for_qtypes(options.qtypes.clone(), options.except_qtypes.clone())
+            } else {
+                entry
+            }
+        })
+        .collect()
+}
+
+// Reads query types like ["AAAA", "~A"] into the types to match and the
+// types to never match ('~' meaning not)
+pub fn parse_qtypes<'a, I: Iterator<Item = &'a str>>(names: I) -> Option<(Vec<u16>, Vec<u16>)> {
+    let mut qtypes = Vec::new();
+    let mut except_qtypes = Vec::new();
+
+    for name in names {
+        match name.trim().strip_prefix('~') {
+            Some(name) => except_qtypes.push(dns_message::type_from_name(name)?),
+            None => qtypes.push(dns_message::type_from_name(name)?),
+        }
+    }
+
+    Some((qtypes, except_qtypes))
+}
+
+fn check_entries(entries: &[Entry], options: &BlockListOptions) -> Result {
+    if entries.is_empty() {
+        return Err(BlockListError::no_entries());
+    }
+
+    if let Some(max_entries) = options.max_entries {
+        if entries.len() > max_entries {
+            return Err(BlockListError::verification_failed(
+                "list has more than max_entries entries",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// How long a response says it stays fresh for. 'max-age' wins over
+// 'Expires' the same as it does for any other HTTP cache.
+fn freshness(cache_control: &Option<String>, expires: &Option<String>) -> Option<Duration> {
+    if let Some(cache_control) = cache_control {
+        for directive in cache_control.split(',') {
+            let directive = directive.trim().to_lowercase();
+            if directive == "no-cache" || directive == "no-store" {
+                return None;
+            }
+            if let Some(age) = directive.strip_prefix("max-age=") {
+                if let Ok(seconds) = age.trim_matches('"').parse::<u64>() {
+                    return Some(Duration::from_secs(seconds));
+                }
+            }
+        }
+    }
+
+    let expires = httpdate::parse_http_date(expires.as_ref()?).ok()?;
+    expires.duration_since(SystemTime::now()).ok()
+}
+
+// Turns a URL into something safe to use as a file name, with a hash on
+// the end so URLs that only differ in their punctuation don't collide
+fn cache_file_name(url: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in url.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    let safe: String = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(100)
+        .collect();
+
+    format!("{}-{:016x}.list", safe, hash)
+}
+
+fn process_lines<I: Iterator<Item = String>>(lines: I, format: &BlockListFormat) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut origin: Option<String> = None;
+    let mut owner: Option<String> = None;
+
+    for line in lines {
+        // RPZ zone files can move the origin part way through
+        if let BlockListFormat::Rpz = format {
+            if let Some(o) = line.trim().strip_prefix("$ORIGIN") {
+                origin = Some(o.trim().trim_end_matches('.').to_lowercase());
+                continue;
+            }
+            if origin.is_none() {
+                origin = rpz_zone(&line);
+            }
+
+            if let Some(entry) = parse_rpz(&line, &origin, &mut owner).and_then(normalize_entry) {
+                // Several A/AAAA records for the same name make up a
+                // single local-data answer
+                if let Some(last) = entries.last_mut() {
+                    if let (Action::LocalData(existing), Action::LocalData(new)) =
+                        (&mut last.action, &entry.action)
+                    {
+                        if last.hostname == entry.hostname {
+                            existing.extend(new.iter().cloned());
+                            continue;
+                        }
+                    }
+                }
+                entries.push(entry);
+            }
+            continue;
+        }
+
+        entries.extend(
+            process_line(&line, format)
+                .into_iter()
+                .filter_map(normalize_entry),
+        );
+    }
+
+    entries
+}
+
+// Puts an entry's hostname into the form queries are matched in, or drops
+// it if it isn't a valid name
+fn normalize_entry(mut entry: Entry) -> Option<Entry> {
+    if entry.regex || entry.network.is_some() {
+        return Some(entry);
+    }
+
+    let (prefix, name) = match entry.hostname.strip_prefix("*.") {
+        Some(name) => ("*.", name),
+        None => ("", entry.hostname.as_str()),
+    };
+
+    match normalize_hostname(name) {
+        Some(name) => {
+            entry.hostname = format!("{}{}", prefix, name);
+            Some(entry)
+        }
+        None => {
+            debug!("Skipping invalid hostname '{}'", entry.hostname);
+            None
+        }
+    }
+}
+
+// Lowercases a name, drops any trailing dot and turns Unicode labels into
+// punycode A-labels, so 'Ads.Example.COM.' and 'ads.example.com' are the
+// same name. Returns None if any label isn't valid in a DNS name.
+pub fn normalize_hostname(hostname: &str) -> Option<String> {
+    let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+    let ascii = if hostname.is_ascii() {
+        hostname.to_ascii_lowercase()
+    } else {
+        idna::domain_to_ascii(hostname).ok()?
+    };
+
+    if ascii.is_empty() || ascii.len() > 253 {
+        return None;
+    }
+
+    for label in ascii.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        // Underscores aren't allowed in hostnames but turn up in plenty of
+        // real names (e.g. '_dmarc'), so they're let through
+        let valid = label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return None;
+        }
+    }
+
+    Some(ascii)
+}
+
+fn process_line(line: &String, format: &BlockListFormat) -> Vec<Entry> {
+    // Adblock lists have their own comment syntax
+    if let BlockListFormat::Adblock = format {
+        return parse_adblock(line).into_iter().collect();
+    }
+
+    if let BlockListFormat::Dnsmasq = format {
+        return parse_dnsmasq(line);
+    }
+
+    if let BlockListFormat::Regex = format {
+        return parse_regex(line).into_iter().collect();
+    }
+
+    if let BlockListFormat::Cidr = format {
+        return parse_cidr(line).into_iter().collect();
+    }
+
+    let no_comments = match strip_comments(line) {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+
+    if let BlockListFormat::Hosts = format {
+        if let Some(host) = extract_hostname(&no_comments) {
+            return vec![Entry::exact(host)];
+        } else {
+            return Vec::new();
+        }
+    } else if let BlockListFormat::OnePerLine = format {
+        return vec![Entry::exact(no_comments)];
+    } else {
+        return Vec::new();
+    }
+}
+
+// Adblock options that limit a rule to some clients or pages, or that
+// rewrite answers. Rules with these are skipped rather than applied to
+// every query.
+const UNSUPPORTED_ADBLOCK_OPTIONS: [&str; 7] = [
+    "badfilter",
+    "client",
+    "ctag",
+    "denyallow",
+    "dnsrewrite",
+    "domain",
+    "app",
+];
+
+// Parses the DNS-relevant subset of Adblock Plus/uBlock filter syntax, e.g.
+// '||example.com^', '@@||example.com^' and '||example.com^$important'. Rules
+// that only make sense in a browser (cosmetic filters, URL paths) are
+// skipped, and options that only matter to a browser are ignored
+fn parse_adblock(line: &str) -> Option<Entry> {
+    let line = line.trim();
+
+    // '!' comments, '[Adblock Plus 2.0]' style headers and '#' comments
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') || line.starts_with('#') {
+        return None;
+    }
+
+    // Cosmetic filters
+    if line.contains("##") || line.contains("#@#") || line.contains("#?#") {
+        return None;
+    }
+
+    let (line, exception) = match line.strip_prefix("@@") {
+        Some(l) => (l, true),
+        None => (line, false),
+    };
+
+    let (pattern, options) = match line.find('$') {
+        Some(i) => (&line[..i], Some(&line[i + 1..])),
+        None => (line, None),
+    };
+
+    let mut important = false;
+    let mut qtypes = Vec::new();
+    let mut except_qtypes = Vec::new();
+    if let Some(options) = options {
+        for option in options.split(',') {
+            let option = option.trim();
+            // Without any '~' (not) in front or '=value' on the end
+            let name = option.trim_start_matches('~').split('=').next();
+            match option {
+                "important" => important = true,
+                // '$dnstype=AAAA|~A' as used by AdGuard
+                o if o.starts_with("dnstype=") => {
+                    let (only, except) = parse_qtypes(o["dnstype=".len()..].split('|'))?;
+                    qtypes = only;
+                    except_qtypes = except;
+                }
+                // Options that narrow down or change what the rule does in
+                // ways we can't follow, so the rule can't be used as is
+                _ if name.is_some_and(|n| UNSUPPORTED_ADBLOCK_OPTIONS.contains(&n)) => return None,
+                // Anything else (e.g. '$third-party' or '$script') only
+                // means something to a browser, and blocking the name
+                // covers it
+                _ => (),
+            }
+        }
+    }
+
+    // '||' covers the domain and its subdomains, a single '|' anchors the
+    // rule to the start of the name so it only covers the domain itself
+    let (pattern, include_subdomains) = match pattern.strip_prefix("||") {
+        Some(p) => (p, true),
+        None => (pattern.strip_prefix('|').unwrap_or(pattern), false),
+    };
+    let hostname = pattern.trim_end_matches('|').trim_end_matches('^');
+
+    let is_hostname = !hostname.is_empty()
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+    if !is_hostname {
+        return None;
+    }
+
+    let entry = Entry {
+        exception,
+        important,
+        ..Entry::domain(hostname.to_string(), include_subdomains)
+    };
+
+    Some(entry.for_qtypes(qtypes, except_qtypes))
+}
+
+// One network per line, e.g. '203.0.113.0/24' or '2001:db8::/32'. Bare
+// addresses are taken to be a network of their own.
+fn parse_cidr(line: &String) -> Option<Entry> {
+    let line = strip_comments(line)?;
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let network = match line.parse::<IpNet>() {
+        Ok(n) => n,
+        Err(_) => match line.parse::<IpAddr>() {
+            Ok(a) => IpNet::from(a),
+            Err(_) => {
+                debug!("Skipping invalid network '{}'", line);
+                return None;
+            }
+        },
+    };
+
+    Some(Entry::network(network))
+}
+
+// One pattern per line, checked here so a single bad pattern doesn't stop
+// the rest of the list compiling into the regex set
+fn parse_regex(line: &String) -> Option<Entry> {
+    let pattern = strip_comments(line)?;
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return None;
+    }
+
+    match Regex::new(pattern) {
+        Ok(_) => Some(Entry::regex(pattern.to_string())),
+        Err(e) => {
+            warn!("Skipping invalid regex block rule '{}': {}", pattern, e);
+            None
+        }
+    }
+}
+
+// Parses dnsmasq config lines. 'address=/example.com/' and
+// 'server=/example.com/' (or 'local=') with no upstream answer NXDOMAIN,
+// while 'address=/example.com/1.2.3.4' answers with that address. Both
+// cover subdomains, and a line can list several domains
+fn parse_dnsmasq(line: &str) -> Vec<Entry> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return Vec::new();
+    }
+
+    let (directive, rest) = match line.find('=') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => return Vec::new(),
+    };
+
+    // The value looks like '/domain/[domain/...]target'
+    let rest = match rest.strip_prefix('/') {
+        Some(r) => r,
+        None => return Vec::new(),
+    };
+    let mut parts: Vec<&str> = rest.split('/').collect();
+    let target = match parts.pop() {
+        Some(t) => t.trim(),
+        None => return Vec::new(),
+    };
+
+    let action = match (directive.trim(), target) {
+        ("address", "") | ("address", "#") => Action::Nxdomain,
+        ("server", "") | ("local", "") => Action::Nxdomain,
+        ("address", ip) => match ip.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => Action::Nxdomain,
+            Ok(ip) => Action::LocalData(vec![ip]),
+            Err(_) => return Vec::new(),
+        },
+        // Anything else (e.g. forwarding to another server) isn't a block
+        _ => return Vec::new(),
+    };
+
+    parts
+        .into_iter()
+        .map(|d| d.trim().trim_end_matches('.'))
+        .filter(|d| !d.is_empty() && *d != "#")
+        .map(|d| Entry {
+            action: action.clone(),
+            ..Entry::domain(d.to_lowercase(), true)
+        })
+        .collect()
+}
+
+// Parses a record from a Response Policy Zone. 'CNAME .' is NXDOMAIN,
+// 'CNAME *.' is NODATA, 'CNAME rpz-passthru.' is an exception and A/AAAA
+// records are local data to answer with. Other triggers and actions aren't
+// supported and are skipped. 'owner' carries the last owner name between
+// lines, for records that leave it out.
+fn parse_rpz(line: &str, origin: &Option<String>, owner: &mut Option<String>) -> Option<Entry> {
+    // ';' starts a comment
+    let line = match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    let mut fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.is_empty() || fields[0].starts_with('$') {
+        return None;
+    }
+
+    // Records that carry on from the previous owner start with whitespace
+    if !line.starts_with(char::is_whitespace) {
+        *owner = Some(fields.remove(0).to_lowercase());
+    }
+    let owner = owner.as_deref()?;
+    if fields.len() < 2 || owner == "@" {
+        return None;
+    }
+
+    // Skip over the optional TTL and class to find the type
+    let type_index = fields
+        .iter()
+        .position(|f| !f.chars().all(|c| c.is_ascii_digit()) && !f.eq_ignore_ascii_case("IN"))?;
+    let rtype = fields[type_index].to_uppercase();
+    let rdata = fields.get(type_index + 1)?;
+
+    // Absolute names include the zone's own name, which needs to go.
+    // Anything outside the zone isn't a trigger.
+    let hostname = match (owner.strip_suffix('.'), origin) {
+        (Some(absolute), Some(origin)) if is_subdomain(absolute, origin) => {
+            &absolute[..absolute.len() - origin.len() - 1]
+        }
+        (Some(_), Some(_)) => return None,
+        (Some(absolute), None) => absolute,
+        (None, _) => owner,
+    };
+
+    // IP, NSDNAME and client triggers live under their own labels
+    if hostname.is_empty() || hostname.split('.').any(|l| l.starts_with("rpz-")) {
+        return None;
+    }
+
+    let mut entry = Entry::exact(hostname.to_string());
+    match (rtype.as_str(), rdata.to_lowercase().as_str()) {
+        ("CNAME", ".") => entry.action = Action::Nxdomain,
+        ("CNAME", "*.") => entry.action = Action::Nodata,
+        ("CNAME", "rpz-passthru.") => entry.exception = true,
+        // 'rpz-drop.' means not answering at all, which falls under the
+        // unsupported actions
+        ("A", ip) | ("AAAA", ip) => match ip.parse::<IpAddr>() {
+            Ok(ip) => entry.action = Action::LocalData(vec![ip]),
+            Err(_) => return None,
+        },
+        _ => return None,
+    }
+
+    Some(entry)
+}
+
+// The zone's name from its SOA record, for zone files without an $ORIGIN
+// (e.g. from 'dig axfr'), which write every owner name out in full
+fn rpz_zone(line: &str) -> Option<String> {
+    if line.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let fields: Vec<&str> = line.split(';').next()?.split_whitespace().collect();
+    let zone = fields.first()?.strip_suffix('.')?;
+    let is_soa = fields[1..]
+        .iter()
+        .find(|f| !f.chars().all(|c| c.is_ascii_digit()) && !f.eq_ignore_ascii_case("IN"))
+        .is_some_and(|t| t.eq_ignore_ascii_case("SOA"));
+
+    if is_soa && !zone.is_empty() {
+        Some(zone.to_lowercase())
+    } else {
+        None
+    }
+}
+
+pub fn is_subdomain(hostname: &str, parent: &str) -> bool {
+    hostname.len() > parent.len()
+        && hostname.ends_with(parent)
+        && hostname.as_bytes()[hostname.len() - parent.len() - 1] == b'.'
+}
+
+fn strip_comments(line: &String) -> Option<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"((^|\s+)#.+)"#).unwrap();
+    }
+    let no_comments = RE.replace(line, "").to_mut().to_string();
+
+    if no_comments == "" {
+        return None;
+    }
+
+    Some(no_comments)
+}
+
+fn extract_hostname(line: &String) -> Option<String> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"^.+\s+"#).unwrap();
+    }
+
+    let hostname = RE.replace(line, "").to_mut().to_string();
+    if hostname == "" {
+        return None;
+    }
+
+    Some(hostname)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_message::{TYPE_A, TYPE_AAAA};
+
+    fn lookup<'a>(
+        block_lists: &'a BlockLists,
+        hostname: &str,
+        qtype: u16,
+        lists: Option<&[String]>,
+    ) -> Option<&'a Action> {
+        let selection = block_lists.select(lists, chrono::Local::now().naive_local());
+        block_lists
+            .explain(hostname, qtype, &selection)
+            .map(|(_, entry)| &entry.action)
+    }
+
+    #[test]
+    fn strip_comments_works() {
+        let line1 = "# A comment here".to_string();
+        let line2 = "Something here # but now a comment".to_string();
+        let line2_correct = "Something here".to_string();
+
+        let line1_res = strip_comments(&line1);
+        assert!(line1_res.is_none());
+
+        let line2_res = strip_comments(&line2).unwrap();
+        assert_eq!(line2_correct, line2_res);
     }
 
     #[test]
@@ -265,4 +1729,572 @@ mod tests {
         assert_eq!(res2, line2_correct);
         assert!(res3.is_none());
     }
+
+    #[test]
+    fn parse_adblock_works() {
+        let block = parse_adblock("||ads.example.com^").unwrap();
+        assert_eq!(block.hostname, "ads.example.com");
+        assert!(block.include_subdomains);
+        assert!(!block.exception);
+
+        let allow = parse_adblock("@@||good.example.com^").unwrap();
+        assert!(allow.exception);
+
+        let important = parse_adblock("||tracker.com^$important").unwrap();
+        assert!(important.important);
+
+        assert!(parse_adblock("! A comment").is_none());
+        assert!(parse_adblock("[Adblock Plus 2.0]").is_none());
+        assert!(parse_adblock("example.com##.banner").is_none());
+        assert!(parse_adblock("||example.com/ads/*").is_none());
+
+        let anchored = parse_adblock("|ads.example.org^").unwrap();
+        assert_eq!(anchored.hostname, "ads.example.org");
+        assert!(!anchored.include_subdomains);
+
+        let browser_only = parse_adblock("||tracker.com^$third-party,~script").unwrap();
+        assert_eq!(browser_only.hostname, "tracker.com");
+        assert!(!browser_only.important && browser_only.include_subdomains);
+        assert!(
+            parse_adblock("||tracker.com^$important,popup")
+                .unwrap()
+                .important
+        );
+        assert!(parse_adblock("||example.com^$badfilter").is_none());
+        assert!(parse_adblock("||example.com^$client=192.168.1.2").is_none());
+        assert!(parse_adblock("||example.com^$domain=~news.com").is_none());
+    }
+
+    #[test]
+    fn adblock_exceptions_work() {
+        let lines = [
+            "||example.com^",
+            "@@||good.example.com^",
+            "||tracker.net^$important",
+            "@@||tracker.net^",
+        ];
+        let entries = process_lines(
+            lines.iter().map(|l| l.to_string()),
+            &BlockListFormat::Adblock,
+        );
+
+        let mut block_lists = BlockLists::new();
+        block_lists.lists.push(BlockList {
+            kind: BlockListKind::File,
+            format: BlockListFormat::Adblock,
+            path: None,
+            url: None,
+            etag: None,
+            last_modified: None,
+            options: BlockListOptions::default(),
+            next_refresh: None,
+            refreshed_at: Some(SystemTime::now()),
+            indexes: Indexes::default(),
+            from_disk: false,
+            entries,
+        });
+
+        assert!(lookup(&block_lists, "example.com", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "good.example.com", TYPE_A, None).is_none());
+        assert!(lookup(&block_lists, "notexample.com", TYPE_A, None).is_none());
+        assert!(lookup(&block_lists, "cdn.tracker.net", TYPE_A, None).is_some());
+    }
+
+    #[test]
+    fn parse_dnsmasq_works() {
+        let nx = parse_dnsmasq("address=/ads.com/tracker.net/");
+        assert_eq!(nx.len(), 2);
+        assert_eq!(nx[1].hostname, "tracker.net");
+        assert_eq!(nx[1].action, Action::Nxdomain);
+        assert!(nx[1].include_subdomains);
+
+        let unspecified = parse_dnsmasq("address=/ads.com/0.0.0.0");
+        assert_eq!(unspecified[0].action, Action::Nxdomain);
+
+        let local = parse_dnsmasq("address=/nas.lan/192.168.1.10");
+        let ip: IpAddr = "192.168.1.10".parse().unwrap();
+        assert_eq!(local[0].action, Action::LocalData(vec![ip]));
+
+        assert_eq!(parse_dnsmasq("server=/ads.com/").len(), 1);
+        assert!(parse_dnsmasq("server=/corp.lan/10.0.0.1").is_empty());
+        assert!(parse_dnsmasq("# address=/ads.com/").is_empty());
+    }
+
+    #[test]
+    fn parse_rpz_works() {
+        let zone = [
+            "$TTL 300",
+            "@ SOA localhost. root.localhost. 1 3600 600 86400 300",
+            "  NS localhost.",
+            "bad.com CNAME .",
+            "*.bad.com CNAME .",
+            "nodata.com 300 IN CNAME *.",
+            "good.bad.com CNAME rpz-passthru.",
+            "nas.lan A 192.168.1.10",
+            "nas.lan AAAA fd00::10",
+            "absolute.com.rpz.example. CNAME . ; with a comment",
+            "32.1.0.0.127.rpz-ip CNAME .",
+            "printer.lan A 192.168.1.11",
+            "\tIN AAAA fd00::11",
+            "dropped.com CNAME rpz-drop.",
+            "outside.notrpz.example. CNAME .",
+        ];
+        let lines = zone.iter().map(|l| l.to_string());
+        let entries = process_lines(
+            std::iter::once("$ORIGIN rpz.example.".to_string()).chain(lines),
+            &BlockListFormat::Rpz,
+        );
+        assert_eq!(entries.len(), 7);
+
+        let mut block_lists = BlockLists::new();
+        block_lists.lists.push(BlockList {
+            kind: BlockListKind::File,
+            format: BlockListFormat::Rpz,
+            path: None,
+            url: None,
+            etag: None,
+            last_modified: None,
+            options: BlockListOptions::default(),
+            next_refresh: None,
+            refreshed_at: Some(SystemTime::now()),
+            indexes: Indexes::default(),
+            from_disk: false,
+            entries,
+        });
+
+        let lookup = |h: &str| lookup(&block_lists, h, TYPE_A, None).cloned();
+        assert_eq!(lookup("bad.com"), Some(Action::Nxdomain));
+        assert_eq!(lookup("www.bad.com"), Some(Action::Nxdomain));
+        assert_eq!(lookup("good.bad.com"), None);
+        assert_eq!(lookup("nodata.com"), Some(Action::Nodata));
+        assert_eq!(lookup("absolute.com"), Some(Action::Nxdomain));
+        assert_eq!(lookup("www.nodata.com"), None);
+
+        let ips = vec!["192.168.1.10".parse().unwrap(), "fd00::10".parse().unwrap()];
+        assert_eq!(lookup("nas.lan"), Some(Action::LocalData(ips)));
+        let ips = vec!["192.168.1.11".parse().unwrap(), "fd00::11".parse().unwrap()];
+        assert_eq!(lookup("printer.lan"), Some(Action::LocalData(ips)));
+        assert_eq!(lookup("dropped.com"), None);
+        assert_eq!(lookup("outside"), None);
+    }
+
+    #[test]
+    fn rpz_zone_comes_from_soa() {
+        // As written out by 'dig axfr', with no $ORIGIN
+        let zone = [
+            "rpz.example. 300 IN SOA localhost. root.localhost. 1 3600 600 86400 300",
+            "rpz.example. 300 IN NS localhost.",
+            "bad.com.rpz.example. 300 IN CNAME .",
+            "*.bad.com.rpz.example. 300 IN CNAME .",
+            "nas.lan.rpz.example. 300 IN A 192.168.1.10",
+            "rpz.example. 300 IN SOA localhost. root.localhost. 1 3600 600 86400 300",
+        ];
+        let entries = process_lines(zone.iter().map(|l| l.to_string()), &BlockListFormat::Rpz);
+        let hostnames: Vec<&str> = entries.iter().map(|e| e.hostname.as_str()).collect();
+        assert_eq!(hostnames, ["bad.com", "*.bad.com", "nas.lan"]);
+
+        assert_eq!(rpz_zone("@ SOA localhost. root.localhost. 1 2 3 4 5"), None);
+        assert_eq!(rpz_zone("bad.com.rpz.example. CNAME ."), None);
+    }
+
+    #[test]
+    fn regex_rules_work() {
+        let lines = ["^ad[0-9]*\\.", "# A comment", "[invalid", "tracking"];
+        let entries = process_lines(lines.iter().map(|l| l.to_string()), &BlockListFormat::Regex);
+        assert_eq!(entries.len(), 2);
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(entries, &BlockListOptions::default())
+            .unwrap();
+        block_lists
+            .add_rules(
+                vec![Entry::domain("example.com".to_string(), true)],
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+
+        assert!(lookup(&block_lists, "ad1.example.org", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "my.tracking.net", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "www.example.com", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "bad.example.org", TYPE_A, None).is_none());
+    }
+
+    #[test]
+    fn regex_rules_are_matched_across_lists() {
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(
+                vec![Entry::regex("^ads\\.".to_string())],
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+        let named = BlockListOptions {
+            name: Some("strict".to_string()),
+            ..BlockListOptions::default()
+        };
+        block_lists
+            .add_rules(vec![Entry::regex("^track".to_string())], &named)
+            .unwrap();
+
+        let strict = ["strict".to_string()];
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "tracker.example.com", TYPE_A, None).is_none());
+        assert!(lookup(&block_lists, "tracker.example.com", TYPE_A, Some(&strict)).is_some());
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, Some(&strict)).is_none());
+
+        // Lists added after a query are matched too
+        block_lists
+            .add_rules(
+                vec![Entry::regex("^metrics\\.".to_string())],
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+        assert!(lookup(&block_lists, "metrics.example.com", TYPE_A, None).is_some());
+    }
+
+    #[test]
+    fn http_lists_fall_back_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = "http://127.0.0.1:1/block.list".to_string();
+
+        let options = BlockListOptions::default();
+
+        let mut block_lists = BlockLists::new();
+        block_lists.set_cache_dir(&dir.path().to_str().unwrap().to_string());
+        assert!(block_lists
+            .add_http(&url, &BlockListFormat::OnePerLine, &options)
+            .is_err());
+
+        let validators = HttpValidators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+        };
+        block_lists.write_cached(&url, b"ads.example.com\n", None, &validators);
+
+        block_lists
+            .add_http(&url, &BlockListFormat::OnePerLine, &options)
+            .unwrap();
+        assert_eq!(block_lists.lists[0].etag, validators.etag);
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+
+        // The download keeps being retried, even with no refresh interval
+        assert!(block_lists.next_refresh().unwrap() <= Instant::now() + PENDING_RETRY);
+        block_lists.lists[0].next_refresh = Some(Instant::now());
+        assert!(block_lists.reload_due_lists().is_err());
+        assert!(block_lists.next_refresh().unwrap() <= Instant::now() + PENDING_RETRY);
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+
+        // A copy that doesn't match the checksum isn't used
+        let options = BlockListOptions {
+            sha256: Some(
+                "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08".to_string(),
+            ),
+            ..BlockListOptions::default()
+        };
+        let mut block_lists = BlockLists::new();
+        block_lists.set_cache_dir(&dir.path().to_str().unwrap().to_string());
+        assert!(block_lists
+            .add_http(&url, &BlockListFormat::OnePerLine, &options)
+            .is_err());
+        assert!(block_lists.lists.is_empty());
+    }
+
+    #[test]
+    fn freshness_works() {
+        let max_age = Some("public, max-age=3600".to_string());
+        let expires = Some("Thu, 01 Jan 1970 00:00:00 GMT".to_string());
+        assert_eq!(
+            freshness(&max_age, &expires),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(freshness(&Some("no-cache".to_string()), &None), None);
+        assert_eq!(freshness(&None, &expires), None);
+
+        let later = SystemTime::now() + Duration::from_secs(7200);
+        let expires = Some(httpdate::fmt_http_date(later));
+        let fresh_for = freshness(&None, &expires).unwrap();
+        assert!(fresh_for > Duration::from_secs(7000));
+    }
+
+    #[test]
+    fn lists_are_scheduled_for_refresh() {
+        let mut block_lists = BlockLists::new();
+        let default = BlockListOptions::default();
+        let hourly = BlockListOptions {
+            refresh_after: Some(60),
+            ..BlockListOptions::default()
+        };
+
+        assert!(block_lists.schedule(&hourly, None).is_some());
+        assert!(block_lists.schedule(&default, None).is_none());
+
+        block_lists.set_refresh(Some(30), Some(10));
+        let next = block_lists.schedule(&default, None).unwrap();
+        let wait = next.duration_since(Instant::now());
+        assert!(wait > Duration::from_secs(29 * 60));
+        assert!(wait <= Duration::from_secs(40 * 60));
+
+        // The server saying the list is fresh for a day wins
+        let day = Duration::from_secs(86400);
+        let next = block_lists.schedule(&hourly, Some(day)).unwrap();
+        assert!(next.duration_since(Instant::now()) > day - Duration::from_secs(60));
+    }
+
+    #[test]
+    fn rules_dont_count_as_loaded() {
+        let options = BlockListOptions::default();
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(vec![Entry::exact("ads.example.com".to_string())], &options)
+            .unwrap();
+        assert!(block_lists.is_loaded());
+
+        // A list from a URL that never came down leaves nothing loaded even
+        // though the rules have entries
+        block_lists.add_pending(
+            BlockListKind::Http,
+            "http://127.0.0.1:9/block.list",
+            &BlockListFormat::OnePerLine,
+            &options,
+        );
+        assert!(!block_lists.is_loaded());
+    }
+
+    #[test]
+    fn pending_lists_are_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("block.list").to_str().unwrap().to_string();
+        let options = BlockListOptions::default();
+
+        let mut block_lists = BlockLists::new();
+        let format = BlockListFormat::OnePerLine;
+        assert!(block_lists.add_file(&path, &format, &options).is_err());
+        block_lists.add_pending(BlockListKind::File, &path, &format, &options);
+        assert!(!block_lists.is_loaded());
+
+        // Still missing, so it stays pending
+        block_lists.lists[0].next_refresh = Some(Instant::now());
+        assert!(block_lists.reload_due_lists().is_err());
+        assert!(!block_lists.is_loaded());
+        assert!(block_lists.next_refresh().unwrap() > Instant::now());
+
+        fs::write(&path, "ads.example.com\n").unwrap();
+        block_lists.lists[0].next_refresh = Some(Instant::now());
+        block_lists.reload_due_lists().unwrap();
+        assert!(block_lists.is_loaded());
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+    }
+
+    #[test]
+    fn verification_works() {
+        let body = b"test";
+
+        let mut options = BlockListOptions {
+            sha256: Some(
+                "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08".to_string(),
+            ),
+            max_size: Some(4),
+            ..BlockListOptions::default()
+        };
+        assert!(verify(body, None, &options).is_ok());
+        assert!(verify(b"Test", None, &options).is_err());
+        assert!(verify(b"tests", None, &options).is_err());
+
+        let signature = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+        options.public_key =
+            Some("RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3".to_string());
+        assert!(verify(body, Some(signature.as_bytes()), &options).is_ok());
+        assert!(verify(body, None, &options).is_err());
+
+        options.sha256 = None;
+        assert!(verify(b"Test", Some(signature.as_bytes()), &options).is_err());
+
+        options.max_entries = Some(1);
+        let entries = [
+            Entry::domain("a.example.com".to_string(), false),
+            Entry::domain("b.example.com".to_string(), false),
+        ];
+        assert!(check_entries(&entries[..1], &options).is_ok());
+        assert!(check_entries(&entries, &options).is_err());
+    }
+
+    #[test]
+    fn compressed_lists_work() {
+        use std::io::Write;
+
+        let list = b"ads.example.com\ntracker.example.com\n";
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(list).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let zstd = zstd::stream::encode_all(&list[..], 0).unwrap();
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(list).unwrap();
+        let xz = xz.finish().unwrap();
+
+        for compressed in [gzip, zstd, xz] {
+            assert_eq!(decompress(compressed.clone(), None).unwrap(), list);
+            assert!(decompress(compressed, Some(10)).is_err());
+        }
+        assert_eq!(decompress(list.to_vec(), None).unwrap(), list);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("block.list.zst");
+        fs::write(&path, zstd::stream::encode_all(&list[..], 0).unwrap()).unwrap();
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_file(
+                &path.to_str().unwrap().to_string(),
+                &BlockListFormat::OnePerLine,
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+        assert!(lookup(&block_lists, "ads.example.com", TYPE_A, None).is_some());
+    }
+
+    #[test]
+    fn normalize_hostname_works() {
+        let cases = [
+            ("Ads.Example.COM", Some("ads.example.com")),
+            ("ads.example.com.", Some("ads.example.com")),
+            ("bücher.example", Some("xn--bcher-kva.example")),
+            ("_dmarc.example.com", Some("_dmarc.example.com")),
+            ("bad..example.com", None),
+            ("bad name.example.com", None),
+            ("", None),
+        ];
+        for (input, expected) in cases.iter() {
+            assert_eq!(normalize_hostname(input).as_deref(), *expected);
+        }
+
+        let lines = ["ADS.example.com.", "bücher.example", "not valid!"];
+        let entries = process_lines(
+            lines.iter().map(|l| l.to_string()),
+            &BlockListFormat::OnePerLine,
+        );
+        assert_eq!(entries.len(), 2);
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(entries, &BlockListOptions::default())
+            .unwrap();
+        assert!(lookup(&block_lists, "Ads.Example.COM", TYPE_A, None).is_some());
+        assert!(lookup(&block_lists, "xn--bcher-kva.example", TYPE_A, None).is_some());
+        assert!(block_lists
+            .add_rules(
+                vec![Entry::domain("a b".to_string(), false)],
+                &BlockListOptions::default()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn qtype_filters_work() {
+        let entry = parse_adblock("||dualstack.example.com^$dnstype=AAAA").unwrap();
+        assert_eq!(entry.qtypes, [TYPE_AAAA]);
+        assert_eq!(entry.action, Action::Nodata);
+        assert!(parse_adblock("||example.com^$dnstype=NOPE").is_none());
+
+        let (qtypes, except_qtypes) = parse_qtypes(["HTTPS", "~A"].iter().copied()).unwrap();
+        assert_eq!(qtypes, [65]);
+        assert_eq!(except_qtypes, [TYPE_A]);
+
+        let options = BlockListOptions {
+            except_qtypes: vec![TYPE_A],
+            ..BlockListOptions::default()
+        };
+        let entries = apply_qtypes(
+            vec![entry, Entry::domain("svc.example.com".to_string(), false)],
+            &options,
+        );
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(entries, &BlockListOptions::default())
+            .unwrap();
+        assert!(lookup(&block_lists, "dualstack.example.com", TYPE_A, None).is_none());
+        assert_eq!(
+            lookup(&block_lists, "dualstack.example.com", TYPE_AAAA, None),
+            Some(&Action::Nodata)
+        );
+        assert!(lookup(&block_lists, "svc.example.com", TYPE_A, None).is_none());
+        assert!(lookup(&block_lists, "svc.example.com", 65, None).is_some());
+    }
+
+    #[test]
+    fn ip_block_lists_work() {
+        let lines = [
+            "# Ad network",
+            "203.0.113.0/24",
+            "2001:db8::1",
+            "not a network",
+        ];
+        let entries = process_lines(lines.iter().map(|l| l.to_string()), &BlockListFormat::Cidr);
+        assert_eq!(entries.len(), 2);
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(entries, &BlockListOptions::default())
+            .unwrap();
+        assert!(lookup(&block_lists, "203.0.113.5", TYPE_A, None).is_none());
+        let selection = block_lists.select(None, chrono::Local::now().naive_local());
+
+        let mut response = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, b'a',
+            b'd', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c, 0x00, 0x01,
+            0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 203, 0, 113, 5,
+        ];
+        let (address, _, _) = block_lists.lookup_addresses(&response, &selection).unwrap();
+        assert_eq!(address, "203.0.113.5".parse::<IpAddr>().unwrap());
+
+        let last = response.len() - 1;
+        response[last - 1] = 114;
+        assert!(block_lists
+            .lookup_addresses(&response, &selection)
+            .is_none());
+    }
+
+    #[test]
+    fn named_lists_are_selected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("strict.list");
+        fs::write(&path, "games.example.com\n").unwrap();
+
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(
+                vec![Entry::domain("ads.example.com".to_string(), false)],
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+        let options = BlockListOptions {
+            name: Some("strict".to_string()),
+            ..BlockListOptions::default()
+        };
+        block_lists
+            .add_file(
+                &path.to_str().unwrap().to_string(),
+                &BlockListFormat::OnePerLine,
+                &options,
+            )
+            .unwrap();
+
+        let strict = ["strict".to_string()];
+        let both = ["default".to_string(), "strict".to_string()];
+        let lookup = |h: &str, lists| lookup(&block_lists, h, TYPE_A, lists).is_some();
+        assert!(lookup("ads.example.com", None));
+        assert!(!lookup("games.example.com", None));
+        assert!(!lookup("ads.example.com", Some(&strict[..])));
+        assert!(lookup("games.example.com", Some(&strict[..])));
+        assert!(lookup("ads.example.com", Some(&both[..])));
+        assert!(lookup("games.example.com", Some(&both[..])));
+    }
 }
@@ -0,0 +1,737 @@
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::dns_message;
+
+// RFC 2308 suggests capping negative answers at somewhere between one and
+// three hours, but a blocked/unblocked domain flip should take effect a bit
+// quicker than that on a home network
+const DEFAULT_NEGATIVE_TTL_MAX: u32 = 900;
+
+// RFC 8767 recommends handing stale answers out with a TTL of 30 seconds
+// and waiting at least that long after a failed refresh before trying the
+// upstreams again
+const STALE_TTL: u32 = 30;
+const FAILURE_RECHECK: Duration = Duration::from_secs(30);
+
+// Popular entries are refreshed once they're into the last tenth of their
+// TTL, and only this many at a time so the prefetcher can't flood upstream
+const PREFETCH_FRACTION: u32 = 10;
+const MAX_PREFETCH_PER_PASS: usize = 16;
+
+// Used when neither max_entries nor max_bytes are set, so the cache can't
+// grow without bound on a long running resolver
+const DEFAULT_MAX_ENTRIES: usize = 10000;
+
+// Rough per-entry bookkeeping overhead on top of the stored messages
+const ENTRY_OVERHEAD: usize = 64;
+
+const SNAPSHOT_MAGIC: &[u8] = b"TDPC";
+const SNAPSHOT_VERSION: u8 = 1;
+
+#[derive(Debug)]
+struct Entry {
+    request: Vec<u8>,
+    response: Vec<u8>,
+    inserted: Instant,
+    ttl: u32,
+    hits: u32,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        // The key is a copy of the question, so is never longer than the request
+        self.request.len() * 2 + self.response.len() + ENTRY_OVERHEAD
+    }
+}
+
+// The cached entries along with the order they were last used in, so the
+// least recently used can be evicted when we hit the size limits
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Vec<u8>, Entry>,
+    lru: BTreeMap<u64, Vec<u8>>,
+    next_use: u64,
+    bytes: usize,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Entry> {
+        self.map.values()
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut Entry> {
+        self.map.values_mut()
+    }
+
+    // Looks up an entry and marks it as the most recently used
+    fn touch(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let entry = self.map.get_mut(key)?;
+
+        self.lru.remove(&entry.last_used);
+        entry.last_used = self.next_use;
+        self.lru.insert(self.next_use, key.to_vec());
+        self.next_use += 1;
+
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, mut entry: Entry) {
+        self.remove(&key);
+
+        entry.last_used = self.next_use;
+        self.next_use += 1;
+        self.bytes += entry.size();
+        self.lru.insert(entry.last_used, key.clone());
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.size();
+
+        Some(entry)
+    }
+
+    fn remove_oldest(&mut self) -> Option<Entry> {
+        let key = self.lru.values().next()?.clone();
+
+        self.remove(&key)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<Entries>,
+    negative_ttl_max: u32,
+    stale_window: u32,
+    prefetch_hits: Option<u32>,
+    snapshot_path: Option<String>,
+    max_entries: usize,
+    max_bytes: Option<usize>,
+    upstream_failed_at: Mutex<Option<Instant>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Cache {
+    pub fn from_config(config: &config::Cache) -> Cache {
+        let negative_ttl_max = match config.negative_ttl_max {
+            Some(n) => n,
+            None => DEFAULT_NEGATIVE_TTL_MAX,
+        };
+
+        let max_entries = match (config.max_entries, config.max_bytes) {
+            (Some(n), _) => n,
+            (None, Some(_)) => usize::MAX,
+            (None, None) => DEFAULT_MAX_ENTRIES,
+        };
+
+        Cache {
+            entries: Mutex::new(Entries::default()),
+            negative_ttl_max,
+            stale_window: config.stale_window.unwrap_or(0),
+            prefetch_hits: config.prefetch_hits,
+            snapshot_path: config.snapshot_path.clone(),
+            max_entries,
+            max_bytes: config.max_bytes,
+            upstream_failed_at: Mutex::new(None),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let key = cache_key(request)?;
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return None,
+        };
+
+        let (elapsed, ttl) = match entries.touch(&key) {
+            Some(entry) => {
+                entry.hits = entry.hits.saturating_add(1);
+                (entry.inserted.elapsed().as_secs() as u32, entry.ttl)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        // Expired entries hang around for the stale window in case the
+        // upstreams go away and we need to fall back on them
+        if elapsed >= ttl.saturating_add(self.stale_window) {
+            entries.remove(&key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if elapsed >= ttl {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        // Hand back a copy with the TTLs counted down and the ID of the
+        // request we're answering
+        let mut response = entries.get(&key)?.response.clone();
+        std::mem::drop(entries);
+
+        if dns_message::age_ttls(&mut response, elapsed).is_err() {
+            return None;
+        }
+        if dns_message::copy_id(request, &mut response).is_err()
+            || dns_message::copy_question(request, &mut response).is_err()
+        {
+            return None;
+        }
+
+        Some(response)
+    }
+
+    pub fn get_stale(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let key = cache_key(request)?;
+        let entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return None,
+        };
+
+        let entry = entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed().as_secs() as u32;
+        if elapsed < entry.ttl || elapsed >= entry.ttl.saturating_add(self.stale_window) {
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        std::mem::drop(entries);
+
+        if dns_message::set_ttls(&mut response, STALE_TTL).is_err() {
+            return None;
+        }
+        if dns_message::copy_id(request, &mut response).is_err()
+            || dns_message::copy_question(request, &mut response).is_err()
+        {
+            return None;
+        }
+
+        Some(response)
+    }
+
+    pub fn prefetch_enabled(&self) -> bool {
+        self.prefetch_hits.is_some()
+    }
+
+    // Returns the requests for popular entries that are about to expire, so
+    // they can be sent upstream again before anyone has to wait on them
+    pub fn prefetch_candidates(&self) -> Vec<Vec<u8>> {
+        let prefetch_hits = match self.prefetch_hits {
+            Some(p) => p,
+            None => return Vec::new(),
+        };
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut candidates = Vec::new();
+        for entry in entries.values_mut() {
+            if candidates.len() >= MAX_PREFETCH_PER_PASS {
+                break;
+            }
+
+            let elapsed = entry.inserted.elapsed().as_secs() as u32;
+            let remaining = entry.ttl.saturating_sub(elapsed);
+            if entry.hits < prefetch_hits || remaining == 0 {
+                continue;
+            }
+            if remaining > entry.ttl / PREFETCH_FRACTION {
+                continue;
+            }
+
+            // Reset the count so a name has to stay popular to keep being
+            // prefetched, and so a failed refresh isn't retried every pass
+            entry.hits = 0;
+            candidates.push(entry.request.clone());
+        }
+
+        candidates
+    }
+
+    pub fn upstream_failed(&self) {
+        if let Ok(mut failed_at) = self.upstream_failed_at.lock() {
+            *failed_at = Some(Instant::now());
+        }
+    }
+
+    pub fn upstream_recovered(&self) {
+        if let Ok(mut failed_at) = self.upstream_failed_at.lock() {
+            *failed_at = None;
+        }
+    }
+
+    pub fn upstream_recently_failed(&self) -> bool {
+        match self.upstream_failed_at.lock() {
+            Ok(failed_at) => match *failed_at {
+                Some(instant) => instant.elapsed() < FAILURE_RECHECK,
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn insert(&self, request: &[u8], response: &[u8]) {
+        let key = match cache_key(request) {
+            Some(k) => k,
+            None => return,
+        };
+
+        let ttl = match self.ttl_for(response) {
+            Some(t) if t > 0 => t,
+            _ => return,
+        };
+
+        let entry = Entry {
+            request: request.to_vec(),
+            response: response.to_vec(),
+            inserted: Instant::now(),
+            ttl,
+            hits: 0,
+            last_used: 0,
+        };
+
+        if let Ok(mut entries) = self.entries.lock() {
+            self.store(&mut entries, key, entry);
+        }
+    }
+
+    // Removes anything that's past both its TTL and the stale window,
+    // returning how many entries went
+    pub fn purge_expired(&self) -> usize {
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return 0,
+        };
+
+        let expired: Vec<Vec<u8>> = entries
+            .map
+            .iter()
+            .filter(|(_, entry)| {
+                let elapsed = entry.inserted.elapsed().as_secs() as u32;
+                elapsed >= entry.ttl.saturating_add(self.stale_window)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            entries.remove(key);
+        }
+
+        expired.len()
+    }
+
+    // Drops every entry, returning how many there were
+    pub fn flush(&self) -> usize {
+        match self.entries.lock() {
+            Ok(mut entries) => {
+                let flushed = entries.len();
+                *entries = Entries::default();
+                flushed
+            }
+            Err(_) => 0,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (size, bytes) = match self.entries.lock() {
+            Ok(e) => (e.len(), e.bytes),
+            Err(_) => (0, 0),
+        };
+
+        CacheStats {
+            entries: size,
+            bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    fn store(&self, entries: &mut Entries, key: Vec<u8>, entry: Entry) {
+        entries.insert(key, entry);
+
+        // Evict the least recently used entries until we're back in bounds
+        loop {
+            let over_bytes = match self.max_bytes {
+                Some(max) => entries.bytes > max,
+                None => false,
+            };
+            if entries.len() <= self.max_entries && !over_bytes {
+                break;
+            }
+            if entries.remove_oldest().is_none() {
+                break;
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot_path.is_some()
+    }
+
+    // Writes every entry that's still worth keeping (including stale ones)
+    // out to the snapshot file, returning how many were written
+    pub fn save_snapshot(&self) -> io::Result<usize> {
+        let path = match &self.snapshot_path {
+            Some(p) => p,
+            None => return Ok(0),
+        };
+
+        // Write to a temporary file first so a crash part way through
+        // doesn't leave us with a truncated snapshot
+        let temp_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+        let saved_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(_) => return Err(io::Error::other("Cache lock poisoned")),
+        };
+
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_u8(SNAPSHOT_VERSION)?;
+        writer.write_u64::<NetworkEndian>(saved_at)?;
+        writer.write_u32::<NetworkEndian>(entries.len() as u32)?;
+
+        for entry in entries.values() {
+            writer.write_u32::<NetworkEndian>(entry.ttl)?;
+            writer.write_u32::<NetworkEndian>(entry.inserted.elapsed().as_secs() as u32)?;
+            writer.write_u16::<NetworkEndian>(entry.request.len() as u16)?;
+            writer.write_all(&entry.request)?;
+            writer.write_u16::<NetworkEndian>(entry.response.len() as u16)?;
+            writer.write_all(&entry.response)?;
+        }
+        let count = entries.len();
+        std::mem::drop(entries);
+
+        writer.flush()?;
+        std::mem::drop(writer);
+        fs::rename(&temp_path, path)?;
+
+        Ok(count)
+    }
+
+    // Loads entries from the snapshot file, counting the time since it was
+    // written against their TTLs. Returns how many entries were loaded
+    pub fn load_snapshot(&self) -> io::Result<usize> {
+        let path = match &self.snapshot_path {
+            Some(p) => p,
+            None => return Ok(0),
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = vec![0; SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC || reader.read_u8()? != SNAPSHOT_VERSION {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "Not a cache snapshot");
+            return Err(e);
+        }
+
+        let saved_at = reader.read_u64::<NetworkEndian>()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let since_save = now.saturating_sub(saved_at);
+
+        let count = reader.read_u32::<NetworkEndian>()?;
+        let mut loaded = 0;
+        for _ in 0..count {
+            let ttl = reader.read_u32::<NetworkEndian>()?;
+            let age = reader.read_u32::<NetworkEndian>()? as u64 + since_save;
+
+            let mut request = vec![0; reader.read_u16::<NetworkEndian>()? as usize];
+            reader.read_exact(&mut request)?;
+            let mut response = vec![0; reader.read_u16::<NetworkEndian>()? as usize];
+            reader.read_exact(&mut response)?;
+
+            // Skip anything that's gone past its stale window while we
+            // were down
+            if age >= ttl as u64 + self.stale_window as u64 {
+                continue;
+            }
+
+            let inserted = match Instant::now().checked_sub(Duration::from_secs(age)) {
+                Some(i) => i,
+                None => continue,
+            };
+            let key = match cache_key(&request) {
+                Some(k) => k,
+                None => continue,
+            };
+
+            let entry = Entry {
+                request,
+                response,
+                inserted,
+                ttl,
+                hits: 0,
+                last_used: 0,
+            };
+
+            if let Ok(mut entries) = self.entries.lock() {
+                self.store(&mut entries, key, entry);
+                loaded += 1;
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    fn ttl_for(&self, response: &[u8]) -> Option<u32> {
+        let rcode = dns_message::rcode(response).ok()?;
+        let nxdomain = dns_message::is_nxdomain(response).ok()?;
+
+        // Only successful answers and NXDOMAINs are worth remembering,
+        // anything else (SERVFAIL, REFUSED...) should be asked again
+        if rcode != 0 && !nxdomain {
+            return None;
+        }
+
+        if !nxdomain {
+            if let Some(ttl) = dns_message::minimum_ttl(response).ok()? {
+                return Some(ttl);
+            }
+        }
+
+        // NXDOMAIN or NODATA. Without an SOA in the authority section we
+        // have nothing to go on, so it isn't cached (RFC 2308 section 5)
+        let ttl = dns_message::negative_ttl(response).ok()??;
+
+        Some(std::cmp::min(ttl, self.negative_ttl_max))
+    }
+}
+
+fn cache_key(request: &[u8]) -> Option<Vec<u8>> {
+    dns_message::normalized_question(request).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Vec<u8> {
+        vec![
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x45,
+            0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00,
+            0x01,
+        ]
+    }
+
+    fn response() -> Vec<u8> {
+        vec![
+            0xe4, 0x72, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65,
+            0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00,
+            0x01, 0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 0x5d,
+            0xb8, 0xd8, 0x22,
+        ]
+    }
+
+    fn cache_config(negative_ttl_max: u32) -> config::Cache {
+        config::Cache {
+            negative_ttl_max: Some(negative_ttl_max),
+            stale_window: Some(3600),
+            prefetch_hits: Some(2),
+            snapshot_path: None,
+            snapshot_interval: None,
+            max_entries: None,
+            max_bytes: None,
+        }
+    }
+
+    fn cache(negative_ttl_max: u32) -> Cache {
+        Cache::from_config(&cache_config(negative_ttl_max))
+    }
+
+    #[test]
+    fn cached_answers_get_request_id() {
+        let c = cache(60);
+        assert!(c.get(&request()).is_none());
+
+        c.insert(&request(), &response());
+
+        let cached = c.get(&request()).unwrap();
+        assert_eq!(cached[0..2], request()[0..2]);
+        assert_eq!(cached[2..12], response()[2..12]);
+        assert_eq!(cached[29..], response()[29..]);
+    }
+
+    #[test]
+    fn cached_answers_echo_question_case() {
+        let c = cache(60);
+        c.insert(&request(), &response());
+
+        // The same name in another mix of case, as 0x20 randomisation sends
+        let mut mixed = request();
+        mixed[13] = b'e';
+        mixed[14] = b'X';
+        mixed[22] = b'O';
+        let cached = c.get(&mixed).unwrap();
+        assert_eq!(cached[12..29], mixed[12..29]);
+        assert_eq!(cached[29..], response()[29..]);
+    }
+
+    #[test]
+    fn cache_keys_keep_the_query_type() {
+        let with_type = |qtype: u16| {
+            let mut r = request();
+            r[25..27].copy_from_slice(&qtype.to_be_bytes());
+            r
+        };
+
+        // HTTPS is 0x0041 and TYPE97 is 0x0061, which only differ by case
+        let https = cache_key(&with_type(65)).unwrap();
+        let type97 = cache_key(&with_type(97)).unwrap();
+        assert_ne!(https, type97);
+        assert_ne!(cache_key(&with_type(1)).unwrap(), https);
+
+        // Names still match whatever their case
+        let mut lower = with_type(65);
+        lower[13] = b'e';
+        assert_eq!(cache_key(&lower).unwrap(), https);
+    }
+
+    #[test]
+    fn negative_answers_are_capped() {
+        let c = cache(60);
+        let nxdomain = dns_message::create_nxdomain(&request(), 3600).unwrap();
+
+        assert_eq!(c.ttl_for(&nxdomain), Some(60));
+        assert_eq!(c.ttl_for(&response()), Some(300));
+    }
+
+    #[test]
+    fn expired_answers_are_served_stale() {
+        let c = cache(60);
+        c.insert(&request(), &response());
+        assert!(c.get_stale(&request()).is_none());
+
+        // Wind the clock back on the entry so it looks expired
+        {
+            let mut entries = c.entries.lock().unwrap();
+            let entry = entries.values_mut().next().unwrap();
+            entry.inserted -= Duration::from_secs(301);
+        }
+
+        assert!(c.get(&request()).is_none());
+
+        let stale = c.get_stale(&request()).unwrap();
+        assert_eq!(dns_message::minimum_ttl(&stale).unwrap(), Some(STALE_TTL));
+    }
+
+    #[test]
+    fn popular_entries_are_prefetched() {
+        let c = cache(60);
+        c.insert(&request(), &response());
+
+        // Not popular enough and not close enough to expiring
+        assert!(c.prefetch_candidates().is_empty());
+        c.get(&request());
+        c.get(&request());
+        assert!(c.prefetch_candidates().is_empty());
+
+        {
+            let mut entries = c.entries.lock().unwrap();
+            let entry = entries.values_mut().next().unwrap();
+            entry.inserted -= Duration::from_secs(280);
+        }
+
+        assert_eq!(c.prefetch_candidates(), vec![request()]);
+
+        // Hit counts start over after a prefetch
+        assert!(c.prefetch_candidates().is_empty());
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = cache_config(60);
+        config.snapshot_path = Some(dir.path().join("cache.bin").to_str().unwrap().to_string());
+
+        let c = Cache::from_config(&config);
+        c.insert(&request(), &response());
+        assert_eq!(c.save_snapshot().unwrap(), 1);
+
+        let restored = Cache::from_config(&config);
+        assert_eq!(restored.load_snapshot().unwrap(), 1);
+
+        let cached = restored.get(&request()).unwrap();
+        assert_eq!(cached[29..], response()[29..]);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut config = cache_config(60);
+        config.max_entries = Some(2);
+        let c = Cache::from_config(&config);
+
+        // Three different questions, by swapping out the start of the name
+        let mut requests = Vec::new();
+        for name in &[b"first", b"secnd", b"third"] {
+            let mut r = request();
+            r[13..18].copy_from_slice(*name);
+            requests.push(r);
+        }
+
+        c.insert(&requests[0], &response());
+        c.insert(&requests[1], &response());
+        assert!(c.get(&requests[0]).is_some());
+        c.insert(&requests[2], &response());
+
+        assert!(c.get(&requests[0]).is_some());
+        assert!(c.get(&requests[1]).is_none());
+        assert!(c.get(&requests[2]).is_some());
+
+        let stats = c.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+
+        assert_eq!(c.flush(), 2);
+        assert!(c.get(&requests[0]).is_none());
+        assert_eq!(c.stats().bytes, 0);
+    }
+}
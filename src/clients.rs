@@ -0,0 +1,288 @@
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::block_list;
+use crate::config::{self, DnsServer};
+
+const ARP_TABLE: &str = "/proc/net/arp";
+
+// How long a read of the ARP table is used for before reading it again, and
+// how soon to read it again for a client that wasn't in it
+const ARP_REFRESH: Duration = Duration::from_secs(60);
+const ARP_RETRY: Duration = Duration::from_secs(5);
+
+// A set of clients that get their own block lists, allowlist and upstreams
+#[derive(Debug)]
+pub struct ClientGroup {
+    pub name: String,
+    networks: Vec<IpNet>,
+    macs: Vec<String>,
+    // The names of the block lists to use, or None for the default lists
+    pub block_lists: Option<Vec<String>>,
+    allow: Vec<String>,
+    pub blocking: bool,
+    dns_servers: Vec<DnsServer>,
+    forwards: Vec<(String, Vec<DnsServer>)>,
+}
+
+impl ClientGroup {
+    fn from_config(config: &config::ClientGroup) -> ClientGroup {
+        let mut networks = Vec::new();
+        let mut macs = Vec::new();
+        for client in &config.clients {
+            if let Ok(network) = client.parse::<IpNet>() {
+                networks.push(network);
+            } else if let Ok(address) = client.parse::<IpAddr>() {
+                networks.push(IpNet::from(address));
+            } else if is_mac(client) {
+                macs.push(client.to_lowercase());
+            } else {
+                warn!("Ignoring client '{}' in group {}", client, config.name);
+            }
+        }
+
+        let allow = config
+            .allow
+            .iter()
+            .filter_map(|domain| block_list::normalize_hostname(domain))
+            .collect();
+
+        let forwards = config
+            .forward
+            .iter()
+            .filter_map(|forward| {
+                // With no servers every query for the domain would fail
+                if forward.dns_server.is_empty() {
+                    warn!(
+                        "Ignoring forward for {} in group {} with no dns_server",
+                        forward.domain, config.name
+                    );
+                    return None;
+                }
+                let domain = block_list::normalize_hostname(&forward.domain)?;
+                Some((domain, forward.dns_server.clone()))
+            })
+            .collect();
+
+        ClientGroup {
+            name: config.name.clone(),
+            networks,
+            macs,
+            block_lists: config.block_lists.clone(),
+            allow,
+            blocking: config.blocking.unwrap_or(true),
+            dns_servers: config.dns_server.clone(),
+            forwards,
+        }
+    }
+
+    // Whether the group's allowlist covers the hostname
+    pub fn allows(&self, hostname: &str) -> bool {
+        let hostname = match block_list::normalize_hostname(hostname) {
+            Some(h) => h,
+            None => return false,
+        };
+
+        self.allow
+            .iter()
+            .any(|domain| *domain == hostname || block_list::is_subdomain(&hostname, domain))
+    }
+
+    // The upstreams a conditional forward sends the hostname to, if one
+    // covers it
+    pub fn forward_for(&self, hostname: &str) -> Option<&[DnsServer]> {
+        let hostname = block_list::normalize_hostname(hostname)?;
+        self.forwards
+            .iter()
+            .find(|(domain, _)| *domain == hostname || block_list::is_subdomain(&hostname, domain))
+            .map(|(_, servers)| servers.as_slice())
+    }
+
+    // The upstreams queries for the hostname should go to, if they're not
+    // the usual ones. Conditional forwards win over the group's own servers.
+    pub fn servers_for(&self, hostname: &str) -> Option<&[DnsServer]> {
+        if let Some(servers) = self.forward_for(hostname) {
+            return Some(servers);
+        }
+
+        if self.dns_servers.is_empty() {
+            None
+        } else {
+            Some(&self.dns_servers)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ArpTable {
+    read_at: Option<Instant>,
+    macs: HashMap<IpAddr, String>,
+}
+
+#[derive(Debug)]
+pub struct ClientGroups {
+    groups: Vec<ClientGroup>,
+    arp_table: Mutex<ArpTable>,
+}
+
+impl ClientGroups {
+    pub fn from_config(config: &[config::ClientGroup]) -> ClientGroups {
+        ClientGroups {
+            groups: config.iter().map(ClientGroup::from_config).collect(),
+            arp_table: Mutex::new(ArpTable::default()),
+        }
+    }
+
+    // The first group (in config order) the client belongs to
+    pub fn group_for(&self, client: IpAddr) -> Option<&ClientGroup> {
+        let client = client.to_canonical();
+
+        let mut mac = None;
+        if self.groups.iter().any(|g| !g.macs.is_empty()) {
+            mac = self.mac_for(client);
+        }
+
+        self.groups.iter().find(|group| {
+            group.networks.iter().any(|n| n.contains(&client))
+                || mac.as_ref().is_some_and(|m| group.macs.contains(m))
+        })
+    }
+
+    // Looks the client up in the kernel's ARP table, which only knows about
+    // clients on the same network segment as us
+    fn mac_for(&self, client: IpAddr) -> Option<String> {
+        let mut arp_table = self.arp_table.lock().ok()?;
+
+        let due = match arp_table.read_at {
+            Some(read_at) => {
+                read_at.elapsed() > ARP_REFRESH
+                    || (!arp_table.macs.contains_key(&client) && read_at.elapsed() > ARP_RETRY)
+            }
+            None => true,
+        };
+        if due {
+            arp_table.macs = match fs::read_to_string(ARP_TABLE) {
+                Ok(contents) => parse_arp_table(&contents),
+                Err(e) => {
+                    debug!("Couldn't read the ARP table: {}", e);
+                    HashMap::new()
+                }
+            };
+            arp_table.read_at = Some(Instant::now());
+        }
+
+        arp_table.macs.get(&client).cloned()
+    }
+}
+
+// Reads /proc/net/arp, which looks like:
+//
+// IP address       HW type     Flags       HW address            Mask     Device
+// 192.168.1.20     0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0
+fn parse_arp_table(contents: &str) -> HashMap<IpAddr, String> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = fields.first()?.parse().ok()?;
+            let mac = fields.get(3)?;
+            // Incomplete entries have an all zero address
+            if !is_mac(mac) || *mac == "00:00:00:00:00:00" {
+                return None;
+            }
+            Some((address, mac.to_lowercase()))
+        })
+        .collect()
+}
+
+fn is_mac(s: &str) -> bool {
+    let parts: Vec<&str> = s.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(name: &str, clients: &[&str]) -> config::ClientGroup {
+        config::ClientGroup {
+            name: name.to_string(),
+            clients: clients.iter().map(|c| c.to_string()).collect(),
+            block_lists: None,
+            allow: vec!["school.example.com".to_string()],
+            blocking: None,
+            dns_server: Vec::new(),
+            forward: vec![config::Forward {
+                domain: "corp.example.com".to_string(),
+                dns_server: vec![DnsServer {
+                    ip_address: "10.0.0.53".to_string(),
+                    port: 853,
+                    hostname: "dns.corp.example.com".to_string(),
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn clients_are_grouped() {
+        let groups = ClientGroups::from_config(&[
+            group("kids", &["192.168.1.16/28", "aa:bb:cc:dd:ee:ff"]),
+            group("servers", &["192.168.1.2"]),
+        ]);
+
+        let group_name = |ip: &str| {
+            groups
+                .group_for(ip.parse().unwrap())
+                .map(|g| g.name.clone())
+        };
+        assert_eq!(group_name("192.168.1.20"), Some("kids".to_string()));
+        assert_eq!(
+            group_name("::ffff:192.168.1.2"),
+            Some("servers".to_string())
+        );
+        assert_eq!(group_name("192.168.1.3"), None);
+
+        let kids = groups.group_for("192.168.1.20".parse().unwrap()).unwrap();
+        assert!(kids.allows("www.school.example.com"));
+        assert!(!kids.allows("example.com"));
+        assert!(kids.servers_for("mail.corp.example.com").is_some());
+        assert!(kids.servers_for("example.com").is_none());
+    }
+
+    #[test]
+    fn forwards_without_servers_are_ignored() {
+        let mut config = group("work", &["192.168.1.40"]);
+        config.forward.push(config::Forward {
+            domain: "lab.example.com".to_string(),
+            dns_server: Vec::new(),
+        });
+        let work = ClientGroup::from_config(&config);
+
+        assert!(work.forward_for("lab.example.com").is_none());
+        assert!(work.servers_for("www.lab.example.com").is_none());
+        assert!(work.forward_for("corp.example.com").is_some());
+    }
+
+    #[test]
+    fn parse_arp_table_works() {
+        let contents =
+            "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.20     0x1         0x2         AA:BB:CC:DD:EE:FF     *        eth0
+192.168.1.21     0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        let macs = parse_arp_table(contents);
+        assert_eq!(macs.len(), 1);
+        assert_eq!(
+            macs.get(&"192.168.1.20".parse::<IpAddr>().unwrap()),
+            Some(&"aa:bb:cc:dd:ee:ff".to_string())
+        );
+    }
+}
@@ -6,14 +6,51 @@ use toml;
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BlockLists {
     pub refresh_after: Option<u64>,
+    pub refresh_jitter: Option<u64>,
+    pub blocked_ttl: Option<u32>,
+    pub cache_dir: Option<String>,
+    pub fail_mode: Option<String>,
+    pub wait_for_lists: Option<bool>,
+    pub inspect_answers: Option<bool>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BlockList {
+    pub name: Option<String>,
     pub list_type: String,
+    // Not needed for IP block lists, which are always CIDRs
+    #[serde(default)]
     pub format: String,
     pub path: Option<String>,
     pub url: Option<String>,
+    pub refresh_after: Option<u64>,
+    pub sha256: Option<String>,
+    pub public_key: Option<String>,
+    pub signature_url: Option<String>,
+    pub max_size: Option<usize>,
+    pub max_entries: Option<usize>,
+    pub qtypes: Option<Vec<String>>,
+    #[serde(default)]
+    pub schedule: Vec<Window>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockRule {
+    pub regex: Option<String>,
+    pub domain: Option<String>,
+    pub include_subdomains: Option<bool>,
+    pub qtypes: Option<Vec<String>>,
+    #[serde(default)]
+    pub schedule: Vec<Window>,
+}
+
+// A time of the week a block list or rule is active for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Window {
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub from: String,
+    pub until: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -29,12 +66,78 @@ pub struct DnsServer {
     pub hostname: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cache {
+    pub negative_ttl_max: Option<u32>,
+    pub stale_window: Option<u32>,
+    pub prefetch_hits: Option<u32>,
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval: Option<u64>,
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RebindProtection {
+    #[serde(default)]
+    pub exempt_domains: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Control {
+    pub host: Option<String>,
+    pub port: u16,
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueryLog {
+    pub path: String,
+    pub format: Option<String>,
+    pub max_size: Option<u64>,
+    pub rotate_after: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Forward {
+    pub domain: String,
+    #[serde(default)]
+    pub dns_server: Vec<DnsServer>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientGroup {
+    pub name: String,
+    // IP addresses, CIDRs or MAC addresses
+    #[serde(default)]
+    pub clients: Vec<String>,
+    pub block_lists: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    pub blocking: Option<bool>,
+    #[serde(default)]
+    pub dns_server: Vec<DnsServer>,
+    #[serde(default)]
+    pub forward: Vec<Forward>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub bind: BindDetails,
     pub block_lists: Option<BlockLists>,
     pub block_list: Vec<BlockList>,
+    #[serde(default)]
+    pub block_rule: Vec<BlockRule>,
+    #[serde(default)]
+    pub ip_block_list: Vec<BlockList>,
     pub dns_server: Vec<DnsServer>,
+    pub cache: Option<Cache>,
+    pub rebind_protection: Option<RebindProtection>,
+    #[serde(default)]
+    pub client_group: Vec<ClientGroup>,
+    pub control: Option<Control>,
+    pub query_log: Option<QueryLog>,
 }
 
 impl Config {
@@ -63,6 +166,28 @@ port = 53
 
 [block_lists]
 refresh_after = 30
+refresh_jitter = 5
+blocked_ttl = 60
+cache_dir = "/var/cache/tinydnsproxy"
+fail_mode = "closed"
+
+[cache]
+negative_ttl_max = 600
+stale_window = 86400
+prefetch_hits = 10
+snapshot_path = "/var/lib/tinydnsproxy/cache.bin"
+max_entries = 5000
+
+[rebind_protection]
+exempt_domains = ["plex.direct"]
+
+[control]
+port = 8053
+
+[query_log]
+path = "/var/log/tinydnsproxy/queries.log"
+format = "tsv"
+rotate_after = 1440
 
 [[block_list]]
 list_type = "file"
@@ -70,9 +195,34 @@ format = "hosts"
 path = "/tmp/block.list"
 
 [[block_list]]
+name = "strict"
 list_type = "file"
 format = "one-per-line"
 path = "/tmp/block.2.list"
+refresh_after = 1440
+max_size = 1048576
+max_entries = 100000
+
+[[block_list.schedule]]
+days = ["sat", "sun"]
+from = "09:00"
+until = "17:00"
+
+[[block_rule]]
+regex = '^ad[0-9]*\.'
+
+[[block_rule]]
+domain = "example.com"
+include_subdomains = true
+qtypes = ["AAAA", "HTTPS"]
+
+[[block_rule]]
+domain = "social.example.com"
+schedule = [{ days = ["weekdays"], from = "22:00", until = "07:00" }]
+
+[[ip_block_list]]
+list_type = "http"
+url = "https://example.com/ad-networks.txt"
 
 [[dns_server]]
 ip_address = "1.1.1.1"
@@ -84,6 +234,29 @@ ip_address = "8.8.8.8"
 port = 853
 hostname = "dns.google"
 
+[[client_group]]
+name = "kids"
+clients = ["192.168.1.16/28", "aa:bb:cc:dd:ee:ff"]
+block_lists = ["default", "strict"]
+allow = ["school.example.com"]
+
+[[client_group]]
+name = "work"
+clients = ["192.168.1.40"]
+
+[[client_group.forward]]
+domain = "corp.example.com"
+
+[[client_group.forward.dns_server]]
+ip_address = "10.0.0.53"
+port = 853
+hostname = "dns.corp.example.com"
+
+[[client_group]]
+name = "servers"
+clients = ["192.168.1.2"]
+blocking = false
+
 "#;
         let servers = vec![
             DnsServer {
@@ -98,18 +271,40 @@ hostname = "dns.google"
             },
         ];
 
-        let lists = vec![
+        let lists = [
             BlockList {
+                name: None,
                 list_type: "file".to_string(),
                 format: "hosts".to_string(),
                 path: Some("/tmp/block.list".to_string()),
                 url: None,
+                refresh_after: None,
+                sha256: None,
+                public_key: None,
+                signature_url: None,
+                max_size: None,
+                max_entries: None,
+                qtypes: None,
+                schedule: Vec::new(),
             },
             BlockList {
+                name: Some("strict".to_string()),
                 list_type: "file".to_string(),
                 format: "one-per-line".to_string(),
                 path: Some("/tmp/block.2.list".to_string()),
                 url: None,
+                refresh_after: Some(1440),
+                sha256: None,
+                public_key: None,
+                signature_url: None,
+                max_size: Some(1048576),
+                max_entries: Some(100000),
+                qtypes: None,
+                schedule: vec![Window {
+                    days: vec!["sat".to_string(), "sun".to_string()],
+                    from: "09:00".to_string(),
+                    until: "17:00".to_string(),
+                }],
             },
         ];
 
@@ -132,13 +327,92 @@ hostname = "dns.google"
             let list_already_done = &lists[i];
             let list_from_config = &c.block_list[i];
 
+            assert_eq!(list_already_done.name, list_from_config.name);
             assert_eq!(list_already_done.list_type, list_from_config.list_type);
             assert_eq!(list_already_done.format, list_from_config.format);
             assert_eq!(list_already_done.path, list_from_config.path);
+            assert_eq!(
+                list_already_done.refresh_after,
+                list_from_config.refresh_after
+            );
+            assert_eq!(list_already_done.max_size, list_from_config.max_size);
+            assert_eq!(list_already_done.max_entries, list_from_config.max_entries);
+            assert_eq!(
+                list_already_done.schedule.len(),
+                list_from_config.schedule.len()
+            );
         }
 
+        assert_eq!(c.block_rule.len(), 3);
+        assert_eq!(c.block_rule[0].regex, Some("^ad[0-9]*\\.".to_string()));
+        assert_eq!(c.block_rule[1].domain, Some("example.com".to_string()));
+        assert_eq!(c.block_rule[1].include_subdomains, Some(true));
+        assert_eq!(
+            c.block_rule[1].qtypes,
+            Some(vec!["AAAA".to_string(), "HTTPS".to_string()])
+        );
+        let window = &c.block_rule[2].schedule[0];
+        assert_eq!(window.days, vec!["weekdays".to_string()]);
+        assert_eq!(window.from, "22:00");
+        assert_eq!(window.until, "07:00");
+
+        assert_eq!(c.ip_block_list.len(), 1);
+        assert_eq!(c.ip_block_list[0].format, "");
+        assert_eq!(
+            c.ip_block_list[0].url,
+            Some("https://example.com/ad-networks.txt".to_string())
+        );
+
         let block_lists = c.block_lists.unwrap();
         let refresh_after = block_lists.refresh_after.unwrap();
         assert_eq!(refresh_after, 30);
+        assert_eq!(block_lists.refresh_jitter, Some(5));
+        assert_eq!(block_lists.blocked_ttl, Some(60));
+        assert_eq!(
+            block_lists.cache_dir,
+            Some("/var/cache/tinydnsproxy".to_string())
+        );
+        assert_eq!(block_lists.fail_mode, Some("closed".to_string()));
+        assert!(block_lists.wait_for_lists.is_none());
+        assert!(block_lists.inspect_answers.is_none());
+
+        let cache = c.cache.unwrap();
+        assert_eq!(cache.negative_ttl_max, Some(600));
+        assert_eq!(cache.stale_window, Some(86400));
+        assert_eq!(cache.prefetch_hits, Some(10));
+        assert_eq!(
+            cache.snapshot_path,
+            Some("/var/lib/tinydnsproxy/cache.bin".to_string())
+        );
+        assert!(cache.snapshot_interval.is_none());
+        assert_eq!(cache.max_entries, Some(5000));
+        assert!(cache.max_bytes.is_none());
+
+        let control = c.control.unwrap();
+        assert!(control.host.is_none());
+        assert_eq!(control.port, 8053);
+        assert!(control.token.is_none());
+
+        let query_log = c.query_log.unwrap();
+        assert_eq!(query_log.format.as_deref(), Some("tsv"));
+        assert!(query_log.max_size.is_none());
+        assert_eq!(query_log.rotate_after, Some(1440));
+
+        let rebind = c.rebind_protection.unwrap();
+        assert_eq!(rebind.exempt_domains, vec!["plex.direct".to_string()]);
+
+        assert_eq!(c.client_group.len(), 3);
+        let kids = &c.client_group[0];
+        assert_eq!(kids.clients.len(), 2);
+        assert_eq!(
+            kids.block_lists,
+            Some(vec!["default".to_string(), "strict".to_string()])
+        );
+        assert_eq!(kids.allow, vec!["school.example.com".to_string()]);
+        let work = &c.client_group[1];
+        assert_eq!(work.forward.len(), 1);
+        assert_eq!(work.forward[0].domain, "corp.example.com");
+        assert_eq!(work.forward[0].dns_server[0].ip_address, "10.0.0.53");
+        assert_eq!(c.client_group[2].blocking, Some(false));
     }
 }
@@ -0,0 +1,745 @@
+use chrono::Local;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{atomic, Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::block_list::{Action, BlockLists};
+use crate::cache::Cache;
+use crate::clients::ClientGroups;
+use crate::config::DnsServer;
+use crate::dns_message::{self, TYPE_A};
+use crate::metrics;
+use crate::pause::Target;
+use crate::tls_connection::{self, UpstreamHealth};
+
+// The control API only listens locally unless told otherwise, since it
+// can switch blocking off
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+// The longest pause allowed, a week
+const MAX_PAUSE_MINUTES: u64 = 7 * 24 * 60;
+
+// A small HTTP API for managing the proxy while it runs. Everything it
+// answers with is JSON.
+pub struct Control {
+    block_lists: Arc<RwLock<Option<Arc<BlockLists>>>>,
+    cache: Option<Arc<Cache>>,
+    groups: Option<Arc<ClientGroups>>,
+    dns_servers: Vec<DnsServer>,
+    // Shared with the reload thread (see Listener)
+    refresh_lock: Arc<Mutex<()>>,
+    // Whether a refresh asked for through the API is still running
+    refreshing: Arc<atomic::AtomicBool>,
+    // Every request has to bring this as a bearer token, if it's set
+    token: Option<String>,
+}
+
+impl Control {
+    pub fn new(
+        block_lists: Arc<RwLock<Option<Arc<BlockLists>>>>,
+        cache: Option<Arc<Cache>>,
+        groups: Option<Arc<ClientGroups>>,
+        dns_servers: Vec<DnsServer>,
+        refresh_lock: Arc<Mutex<()>>,
+    ) -> Control {
+        Control {
+            block_lists,
+            cache,
+            groups,
+            dns_servers,
+            refresh_lock,
+            refreshing: Arc::new(atomic::AtomicBool::new(false)),
+            token: None,
+        }
+    }
+
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    // Answers requests until told to stop. Also where pauses are ended
+    // once they're up, so that's logged on time.
+    pub fn serve(&self, server: &Server, should_stop: &atomic::AtomicBool) {
+        while !should_stop.load(atomic::Ordering::Relaxed) {
+            if let Some(bl) = self.current() {
+                bl.pauses().expire();
+            }
+
+            match server.recv_timeout(Duration::from_secs(1)) {
+                Ok(Some(request)) => self.respond(request),
+                Ok(None) => continue,
+                Err(e) => warn!("Control API error: {}", e),
+            }
+        }
+    }
+
+    fn respond(&self, request: Request) {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str())
+        };
+        let authorized = self.authorize(
+            request.method(),
+            header("Content-Type"),
+            header("Authorization"),
+        );
+
+        let (status, body) = match authorized {
+            Err(e) => e,
+            Ok(_) if *request.method() == Method::Get && request.url() == "/metrics" => {
+                return self.respond_metrics(request);
+            }
+            Ok(_) => self.handle(request.method(), request.url()),
+        };
+        debug!(
+            "Control API: {} {} -> {}",
+            request.method(),
+            request.url(),
+            status
+        );
+
+        let mut response = Response::from_string(body.to_string()).with_status_code(status);
+        if let Ok(header) = Header::from_bytes("Content-Type", "application/json") {
+            response = response.with_header(header);
+        }
+        if let Err(e) = request.respond(response) {
+            debug!("Couldn't answer control API request: {}", e);
+        }
+    }
+
+    // Metrics are in the Prometheus text format rather than JSON
+    fn respond_metrics(&self, request: Request) {
+        let current = self.current();
+        let body = metrics::render(current.as_deref(), self.cache.as_deref());
+
+        let mut response = Response::from_string(body);
+        if let Ok(header) = Header::from_bytes("Content-Type", "text/plain; version=0.0.4") {
+            response = response.with_header(header);
+        }
+        if let Err(e) = request.respond(response) {
+            debug!("Couldn't answer metrics request: {}", e);
+        }
+    }
+
+    // Web pages can get a browser to send simple requests anywhere, so
+    // anything that changes something has to say it's JSON, which a page
+    // can't do for another site without the API agreeing to it. With a
+    // token set, every request has to bring it too.
+    fn authorize(
+        &self,
+        method: &Method,
+        content_type: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<(), (u16, Value)> {
+        if let Some(token) = &self.token {
+            let bearer = authorization.and_then(|a| a.strip_prefix("Bearer "));
+            if bearer.map(str::trim) != Some(token.as_str()) {
+                return Err(error(401, "A valid token is needed"));
+            }
+        }
+
+        let is_json = content_type
+            .and_then(|c| c.split(';').next())
+            .is_some_and(|c| c.trim().eq_ignore_ascii_case("application/json"));
+        if *method == Method::Post && !is_json {
+            return Err(error(415, "POST requests should be application/json"));
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, method: &Method, url: &str) -> (u16, Value) {
+        let (path, params) = parse_url(url);
+
+        match (method, path) {
+            (Method::Get, "/lists") => self.lists(),
+            (Method::Post, "/refresh") => self.refresh(&params),
+            (Method::Get, "/test") => self.test(&params),
+            (Method::Post, "/cache/flush") => self.flush_cache(),
+            (Method::Get, "/upstreams") => self.upstreams(),
+            (Method::Get, "/pause") => self.paused(),
+            (Method::Post, "/pause") => self.pause(&params),
+            (Method::Post, "/resume") => self.resume(&params),
+            _ => error(404, "Not found"),
+        }
+    }
+
+    fn current(&self) -> Option<Arc<BlockLists>> {
+        match self.block_lists.read() {
+            Ok(optional) => optional.clone(),
+            Err(_) => None,
+        }
+    }
+
+    // Works out what a request is about, checking any list it names exists
+    fn target(&self, params: &HashMap<String, String>) -> Result<Target, (u16, Value)> {
+        let id = match params.get("list") {
+            Some(id) => id,
+            None => return Ok(Target::All),
+        };
+
+        let bl = self.current().ok_or_else(|| error(404, "No block lists"))?;
+        if bl.lists.iter().any(|list| list.id() == id) {
+            Ok(Target::List(id.clone()))
+        } else {
+            Err(error(404, "No such block list"))
+        }
+    }
+
+    fn lists(&self) -> (u16, Value) {
+        let bl = match self.current() {
+            Some(bl) => bl,
+            None => return (200, json!({ "lists": [] })),
+        };
+
+        let now = Instant::now();
+        let paused = bl.pauses().paused();
+        let lists: Vec<Value> = bl
+            .lists
+            .iter()
+            .map(|list| {
+                json!({
+                    "id": list.id(),
+                    "kind": list.kind.name(),
+                    "format": list.format.name(),
+                    "entries": list.entries.len(),
+                    "refreshed_at": list.refreshed_at.and_then(unix_time),
+                    "next_refresh_in": list
+                        .next_refresh
+                        .map(|n| n.saturating_duration_since(now).as_secs()),
+                    "paused": paused.iter().any(|(target, _)| target.is_list(list.id())),
+                })
+            })
+            .collect();
+
+        (200, json!({ "lists": lists }))
+    }
+
+    // Starts a refresh in the background, since downloading lists can take
+    // a while and this thread answers every request. Only one runs at a
+    // time, and /lists shows when it's done.
+    fn refresh(&self, params: &HashMap<String, String>) -> (u16, Value) {
+        let target = match self.target(params) {
+            Ok(t) => t,
+            Err(e) => return e,
+        };
+        if self.current().is_none() {
+            return error(404, "No block lists");
+        }
+        if self.refreshing.swap(true, atomic::Ordering::SeqCst) {
+            return error(409, "A refresh is already running");
+        }
+
+        match &target {
+            Target::All => info!("Refreshing block lists from the control API"),
+            Target::List(id) => info!("Refreshing block list {} from the control API", id),
+        }
+        let block_lists = Arc::clone(&self.block_lists);
+        let refresh_lock = Arc::clone(&self.refresh_lock);
+        let refreshing = Arc::clone(&self.refreshing);
+        let id = target_list(&target).map(str::to_string);
+        thread::spawn(move || {
+            refresh_lists(&block_lists, &refresh_lock, id.as_deref());
+            refreshing.store(false, atomic::Ordering::SeqCst);
+        });
+
+        (
+            202,
+            json!({ "list": target_list(&target), "refreshing": true }),
+        )
+    }
+
+    // Whether a query would be blocked, and why. Goes through the same
+    // steps handle_request does, apart from looking at the answer.
+    fn test(&self, params: &HashMap<String, String>) -> (u16, Value) {
+        let domain = match params.get("domain") {
+            Some(d) => d,
+            None => return error(400, "'domain' is needed"),
+        };
+        let qtype = match params.get("type") {
+            Some(t) => match dns_message::type_from_name(t) {
+                Some(q) => q,
+                None => return error(400, "Unknown query type"),
+            },
+            None => TYPE_A,
+        };
+        let client = match params.get("client").map(|c| c.parse::<IpAddr>()) {
+            Some(Ok(c)) => Some(c),
+            Some(Err(_)) => return error(400, "'client' should be an IP address"),
+            None => None,
+        };
+
+        let group = match (&self.groups, client) {
+            (Some(groups), Some(client)) => groups.group_for(client),
+            _ => None,
+        };
+        let mut result = json!({
+            "domain": domain,
+            "type": qtype,
+            "client_group": group.map(|g| g.name.as_str()),
+            "blocked": false,
+        });
+
+        let bl = match self.current() {
+            Some(bl) if bl.is_loaded() => bl,
+            _ => {
+                result["reason"] = json!("No block lists have loaded");
+                return (200, result);
+            }
+        };
+
+        let reason = match group {
+            _ if bl.is_paused() => "Blocking is paused",
+            Some(g) if !g.blocking => "Blocking is off for the client group",
+            Some(g) if g.allows(domain) => "Allowed for the client group",
+            _ => {
+                let lists = group.and_then(|g| g.block_lists.as_deref());
+                let selection = bl.select(lists, Local::now().naive_local());
+                match bl.explain(domain, qtype, &selection) {
+                    Some((list, entry)) => {
+                        result["blocked"] = json!(true);
+                        result["list"] = json!(list.id());
+                        result["rule"] = json!(entry.hostname);
+                        result["action"] = action_value(&entry.action);
+                        return (200, result);
+                    }
+                    None => "Not blocked by any list",
+                }
+            }
+        };
+        result["reason"] = json!(reason);
+
+        (200, result)
+    }
+
+    fn flush_cache(&self) -> (u16, Value) {
+        match &self.cache {
+            Some(cache) => {
+                let flushed = cache.flush();
+                info!("Flushed {} entries from the cache", flushed);
+                (200, json!({ "flushed": flushed }))
+            }
+            None => error(404, "The cache is off"),
+        }
+    }
+
+    // Every configured upstream, along with any client group upstreams
+    // that have been used
+    fn upstreams(&self) -> (u16, Value) {
+        let mut health = tls_connection::upstream_health();
+        for server in &self.dns_servers {
+            let key = format!("{}:{}", server.ip_address, server.port);
+            if !health.iter().any(|(k, _)| *k == key) {
+                let unused = UpstreamHealth {
+                    hostname: server.hostname.clone(),
+                    ..UpstreamHealth::default()
+                };
+                health.push((key, unused));
+            }
+        }
+
+        let upstreams: Vec<Value> = health
+            .iter()
+            .map(|(address, h)| {
+                json!({
+                    "address": address,
+                    "hostname": h.hostname,
+                    "healthy": h.is_healthy(),
+                    "successes": h.successes,
+                    "failures": h.failures,
+                    "last_latency_ms": h.last_latency.map(|l| l.as_millis() as u64),
+                    "last_error": h.last_error,
+                    "last_success": h.last_success.and_then(unix_time),
+                    "last_failure": h.last_failure.and_then(unix_time),
+                })
+            })
+            .collect();
+
+        (200, json!({ "upstreams": upstreams }))
+    }
+
+    fn paused(&self) -> (u16, Value) {
+        let bl = match self.current() {
+            Some(bl) => bl,
+            None => return (200, json!({ "paused": [] })),
+        };
+
+        let paused: Vec<Value> = bl
+            .pauses()
+            .paused()
+            .into_iter()
+            .map(|(target, remaining)| {
+                json!({
+                    "list": target_list(&target),
+                    "remaining_seconds": remaining.as_secs(),
+                })
+            })
+            .collect();
+
+        (200, json!({ "paused": paused }))
+    }
+
+    fn pause(&self, params: &HashMap<String, String>) -> (u16, Value) {
+        let minutes = match params.get("minutes").and_then(|m| m.parse::<u64>().ok()) {
+            Some(m) if m > 0 && m <= MAX_PAUSE_MINUTES => m,
+            _ => return error(400, "'minutes' should be a whole number from 1 to 10080"),
+        };
+        let target = match self.target(params) {
+            Ok(t) => t,
+            Err(e) => return e,
+        };
+        let bl = match self.current() {
+            Some(bl) => bl,
+            None => return error(404, "No block lists"),
+        };
+
+        if !bl
+            .pauses()
+            .pause(target.clone(), Duration::from_secs(minutes * 60))
+        {
+            return error(500, "Couldn't start the pause");
+        }
+        (
+            200,
+            json!({ "list": target_list(&target), "minutes": minutes }),
+        )
+    }
+
+    fn resume(&self, params: &HashMap<String, String>) -> (u16, Value) {
+        let target = match self.target(params) {
+            Ok(t) => t,
+            Err(e) => return e,
+        };
+        let resumed = match self.current() {
+            Some(bl) => bl.pauses().resume(&target),
+            None => false,
+        };
+
+        (
+            200,
+            json!({ "list": target_list(&target), "resumed": resumed }),
+        )
+    }
+}
+
+// Refreshes on a copy and swaps it in if that worked, the same as the
+// reload thread. The copy is taken with the refresh lock held, so it
+// starts from whatever the last refresh left.
+fn refresh_lists(
+    block_lists: &RwLock<Option<Arc<BlockLists>>>,
+    refresh_lock: &Mutex<()>,
+    id: Option<&str>,
+) {
+    let _refreshing = refresh_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    let current = match block_lists.read() {
+        Ok(optional) => optional.clone(),
+        Err(_) => None,
+    };
+    let mut bl = match current {
+        Some(bl) => (*bl).clone(),
+        None => return,
+    };
+
+    match bl.refresh(id) {
+        Ok(_) => match block_lists.write() {
+            Ok(mut bl_option) => *bl_option = Some(Arc::new(bl)),
+            Err(_) => warn!("Couldn't swap in the refreshed block lists"),
+        },
+        Err(e) => warn!("Couldn't refresh block lists: {}", e),
+    }
+}
+
+// Pauses of all blocking are shown with a null list
+fn target_list(target: &Target) -> Option<&str> {
+    match target {
+        Target::All => None,
+        Target::List(id) => Some(id),
+    }
+}
+
+fn action_value(action: &Action) -> Value {
+    match action {
+        Action::Nxdomain => json!("nxdomain"),
+        Action::Nodata => json!("nodata"),
+        Action::LocalData(addresses) => json!({ "local_data": addresses }),
+    }
+}
+
+fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+// Splits a request URL into its path and query parameters
+fn parse_url(url: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url, ""),
+    };
+
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (percent_decode(&pair[..i]), percent_decode(&pair[i + 1..])),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect();
+
+    (path, params)
+}
+
+// List ids are often URLs, so need decoding from query strings
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok());
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_list::{BlockListFormat, BlockListKind, BlockListOptions, Entry};
+    use crate::config;
+
+    fn control() -> Control {
+        let mut block_lists = BlockLists::new();
+        block_lists
+            .add_rules(
+                vec![Entry::domain("ads.example.com".to_string(), false)],
+                &BlockListOptions::default(),
+            )
+            .unwrap();
+        let cache = Cache::from_config(&config::Cache {
+            negative_ttl_max: None,
+            stale_window: None,
+            prefetch_hits: None,
+            snapshot_path: None,
+            snapshot_interval: None,
+            max_entries: None,
+            max_bytes: None,
+        });
+        let servers = vec![DnsServer {
+            ip_address: "192.0.2.53".to_string(),
+            port: 853,
+            hostname: "dns.example.com".to_string(),
+        }];
+
+        Control::new(
+            Arc::new(RwLock::new(Some(Arc::new(block_lists)))),
+            Some(Arc::new(cache)),
+            None,
+            servers,
+            Arc::new(Mutex::new(())),
+        )
+    }
+
+    #[test]
+    fn pausing_works() {
+        let control = control();
+        let bl = control.current().unwrap();
+
+        let (status, _) = control.handle(&Method::Post, "/pause?minutes=5");
+        assert_eq!(status, 200);
+        assert!(bl.is_paused());
+        assert!(bl
+            .explain(
+                "ads.example.com",
+                TYPE_A,
+                &bl.select(None, Local::now().naive_local())
+            )
+            .is_none());
+
+        let (status, body) = control.handle(&Method::Get, "/pause");
+        assert_eq!(status, 200);
+        assert_eq!(body["paused"][0]["list"], Value::Null);
+        assert!(body["paused"][0]["remaining_seconds"].as_u64().unwrap() > 290);
+
+        let (status, body) = control.handle(&Method::Post, "/resume");
+        assert_eq!(status, 200);
+        assert_eq!(body["resumed"], true);
+        assert!(bl
+            .explain(
+                "ads.example.com",
+                TYPE_A,
+                &bl.select(None, Local::now().naive_local())
+            )
+            .is_some());
+
+        let (status, _) = control.handle(&Method::Post, "/pause?minutes=5&list=block_rule");
+        assert_eq!(status, 200);
+        assert!(!bl.is_paused());
+        assert!(bl
+            .explain(
+                "ads.example.com",
+                TYPE_A,
+                &bl.select(None, Local::now().naive_local())
+            )
+            .is_none());
+
+        assert_eq!(control.handle(&Method::Post, "/pause?minutes=0").0, 400);
+        assert_eq!(control.handle(&Method::Post, "/pause?minutes=10081").0, 400);
+        assert_eq!(
+            control
+                .handle(&Method::Post, "/pause?minutes=18446744073709551615")
+                .0,
+            400
+        );
+        assert_eq!(
+            control
+                .handle(&Method::Post, "/pause?minutes=5&list=nope")
+                .0,
+            404
+        );
+        assert_eq!(control.handle(&Method::Get, "/nope").0, 404);
+    }
+
+    #[test]
+    fn admin_endpoints_work() {
+        let control = control();
+
+        let (status, body) = control.handle(&Method::Get, "/lists");
+        assert_eq!(status, 200);
+        assert_eq!(body["lists"][0]["id"], "block_rule");
+        assert_eq!(body["lists"][0]["entries"], 1);
+        assert!(body["lists"][0]["refreshed_at"].is_u64());
+
+        let (_, body) = control.handle(&Method::Get, "/test?domain=Ads.Example.com");
+        assert_eq!(body["blocked"], true);
+        assert_eq!(body["list"], "block_rule");
+        assert_eq!(body["rule"], "ads.example.com");
+        assert_eq!(body["action"], "nxdomain");
+        let (_, body) = control.handle(&Method::Get, "/test?domain=example.com&type=AAAA");
+        assert_eq!(body["blocked"], false);
+        assert_eq!(body["type"], 28);
+        assert_eq!(
+            control.handle(&Method::Get, "/test?domain=a&type=NOPE").0,
+            400
+        );
+        assert_eq!(
+            control.handle(&Method::Get, "/test?domain=a&client=x").0,
+            400
+        );
+
+        assert_eq!(control.handle(&Method::Post, "/refresh?list=nope").0, 404);
+
+        let (status, body) = control.handle(&Method::Post, "/cache/flush");
+        assert_eq!(status, 200);
+        assert_eq!(body["flushed"], 0);
+
+        let (_, body) = control.handle(&Method::Get, "/upstreams");
+        let upstream = &body["upstreams"][0];
+        assert_eq!(upstream["address"], "192.0.2.53:853");
+        assert_eq!(upstream["hostname"], "dns.example.com");
+        assert_eq!(upstream["healthy"], true);
+    }
+
+    #[test]
+    fn requests_are_authorized() {
+        let mut control = control();
+        let json = Some("application/json; charset=utf-8");
+
+        assert!(control.authorize(&Method::Get, None, None).is_ok());
+        assert!(control.authorize(&Method::Post, json, None).is_ok());
+        assert_eq!(
+            control
+                .authorize(&Method::Post, Some("text/plain"), None)
+                .unwrap_err()
+                .0,
+            415
+        );
+        assert_eq!(
+            control.authorize(&Method::Post, None, None).unwrap_err().0,
+            415
+        );
+
+        control.set_token(Some("s3cret".to_string()));
+        assert!(control
+            .authorize(&Method::Post, json, Some("Bearer s3cret"))
+            .is_ok());
+        assert_eq!(
+            control.authorize(&Method::Get, None, None).unwrap_err().0,
+            401
+        );
+        assert_eq!(
+            control
+                .authorize(&Method::Get, None, Some("Bearer nope"))
+                .unwrap_err()
+                .0,
+            401
+        );
+    }
+
+    #[test]
+    fn refreshes_only_swap_when_they_work() {
+        let control = control();
+        let before = control.current().unwrap();
+        refresh_lists(&control.block_lists, &control.refresh_lock, None);
+        let after = control.current().unwrap();
+        assert!(!Arc::ptr_eq(&before, &after));
+
+        // A list that still can't be loaded fails the refresh, so the lists
+        // in use are kept
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("block.list");
+        let mut bl = (*after).clone();
+        bl.add_pending(
+            BlockListKind::File,
+            path.to_str().unwrap(),
+            &BlockListFormat::OnePerLine,
+            &BlockListOptions::default(),
+        );
+        *control.block_lists.write().unwrap() = Some(Arc::new(bl));
+        let before = control.current().unwrap();
+        refresh_lists(&control.block_lists, &control.refresh_lock, None);
+        assert!(Arc::ptr_eq(&before, &control.current().unwrap()));
+
+        let (status, body) = control.handle(&Method::Post, "/refresh");
+        assert_eq!(status, 202);
+        assert_eq!(body["refreshing"], true);
+    }
+
+    #[test]
+    fn parse_url_works() {
+        let (path, params) = parse_url("/pause?minutes=5&list=https%3A%2F%2Fexample.com%2Fa+b");
+        assert_eq!(path, "/pause");
+        assert_eq!(params.get("minutes"), Some(&"5".to_string()));
+        assert_eq!(
+            params.get("list"),
+            Some(&"https://example.com/a b".to_string())
+        );
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
@@ -1,12 +1,79 @@
-use byteorder::{NetworkEndian, ReadBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryInto;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::io::SeekFrom;
+use std::net::IpAddr;
 
 use crate::error::DnsMessageError;
 
 type HostnameResult = std::result::Result<String, DnsMessageError>;
 type NxDomainResult = std::result::Result<Vec<u8>, DnsMessageError>;
+type Result<T> = std::result::Result<T, DnsMessageError>;
+
+pub const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+
+// Names for the query types people are likely to want to write in a config
+// or list. Anything else can be given as 'TYPE<number>' (RFC 3597).
+const TYPE_NAMES: [(&str, u16); 14] = [
+    ("A", TYPE_A),
+    ("NS", 2),
+    ("CNAME", TYPE_CNAME),
+    ("SOA", TYPE_SOA),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", TYPE_AAAA),
+    ("SRV", 33),
+    ("NAPTR", 35),
+    ("DS", 43),
+    ("SVCB", 64),
+    ("HTTPS", 65),
+    ("ANY", 255),
+];
+
+const CLASS_IN: u16 = 1;
+const HEADER_LENGTH: usize = 12;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+const RCODE_NAMES: [(&str, u8); 6] = [
+    ("NOERROR", RCODE_NOERROR),
+    ("FORMERR", 1),
+    ("SERVFAIL", RCODE_SERVFAIL),
+    ("NXDOMAIN", RCODE_NXDOMAIN),
+    ("NOTIMP", 4),
+    ("REFUSED", 5),
+];
+
+// Names can legitimately chain a few compression pointers together, but
+// anything beyond this is almost certainly a loop
+const MAX_POINTER_JUMPS: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct ResourceRecord {
+    pub section: Section,
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub ttl_offset: usize,
+    pub rdata_offset: usize,
+    pub rdata_length: usize,
+}
 
 pub fn hostname_from_bytes(bytes: &[u8]) -> HostnameResult {
     let mut cursor = Cursor::new(bytes);
@@ -63,19 +130,465 @@ pub fn hostname_from_bytes(bytes: &[u8]) -> HostnameResult {
     Ok(as_string)
 }
 
-pub fn create_nxdomain(request: &[u8]) -> NxDomainResult {
-    let mut output = Vec::from(request);
+pub fn create_nxdomain(request: &[u8], ttl: u32) -> NxDomainResult {
+    create_negative(request, RCODE_NXDOMAIN, ttl)
+}
+
+pub fn create_nodata(request: &[u8], ttl: u32) -> NxDomainResult {
+    create_negative(request, RCODE_NOERROR, ttl)
+}
+
+// Answers the question with the given addresses. Only the addresses that
+// match the question type are used, so an A query for a name that only has
+// IPv6 addresses gets a NODATA answer
+pub fn create_local_data(request: &[u8], addresses: &[IpAddr], ttl: u32) -> NxDomainResult {
+    let qtype = question_type(request)?;
+    let answers: Vec<&IpAddr> = addresses
+        .iter()
+        .filter(|a| match a {
+            IpAddr::V4(_) => qtype == TYPE_A,
+            IpAddr::V6(_) => qtype == TYPE_AAAA,
+        })
+        .collect();
+
+    if answers.is_empty() {
+        return create_nodata(request, ttl);
+    }
+
+    let question_end = question_end(request)?;
+    let mut output = Vec::from(&request[..question_end]);
+    let mut cursor = Cursor::new(&mut output);
+
+    cursor.seek(SeekFrom::Start(2))?;
+    cursor.write_all(&[0x81, 0x80 | RCODE_NOERROR])?;
+
+    cursor.seek(SeekFrom::Start(6))?;
+    cursor.write_u16::<NetworkEndian>(answers.len() as u16)?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+
+    cursor.seek(SeekFrom::End(0))?;
+    for answer in answers {
+        cursor.write_u16::<NetworkEndian>(0xc000 | HEADER_LENGTH as u16)?;
+        cursor.write_u16::<NetworkEndian>(qtype)?;
+        cursor.write_u16::<NetworkEndian>(CLASS_IN)?;
+        cursor.write_u32::<NetworkEndian>(ttl)?;
+        match answer {
+            IpAddr::V4(ip) => {
+                cursor.write_u16::<NetworkEndian>(4)?;
+                cursor.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                cursor.write_u16::<NetworkEndian>(16)?;
+                cursor.write_all(&ip.octets())?;
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+fn create_negative(request: &[u8], rcode: u8, ttl: u32) -> NxDomainResult {
+    // Only keep the header and question, anything the client sent in the
+    // additional section (e.g. EDNS options) doesn't belong in our answer
+    let question_end = question_end(request)?;
+    let mut output = Vec::from(&request[..question_end]);
+    let mut cursor = Cursor::new(&mut output);
+    let response_bytes = vec![0x81, 0x80 | rcode];
+
+    // We replace the flags to make it look like a response with our rcode
+    cursor.seek(SeekFrom::Start(2))?;
+
+    cursor.write_all(&response_bytes)?;
+
+    // No answers, a single authority record and no additional records
+    cursor.seek(SeekFrom::Start(6))?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+    cursor.write_u16::<NetworkEndian>(1)?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+
+    // Append a synthetic SOA so clients can negatively cache the answer
+    // (RFC 2308). The owner is a pointer back to the question name and
+    // the MNAME and RNAME are both the root
+    cursor.seek(SeekFrom::End(0))?;
+    cursor.write_u16::<NetworkEndian>(0xc000 | HEADER_LENGTH as u16)?;
+    cursor.write_u16::<NetworkEndian>(TYPE_SOA)?;
+    cursor.write_u16::<NetworkEndian>(CLASS_IN)?;
+    cursor.write_u32::<NetworkEndian>(ttl)?;
+    cursor.write_u16::<NetworkEndian>(22)?;
+    cursor.write_all(&[0x0, 0x0])?;
+    cursor.write_u32::<NetworkEndian>(1)?; // Serial
+    cursor.write_u32::<NetworkEndian>(ttl)?; // Refresh
+    cursor.write_u32::<NetworkEndian>(ttl)?; // Retry
+    cursor.write_u32::<NetworkEndian>(ttl)?; // Expire
+    cursor.write_u32::<NetworkEndian>(ttl)?; // Minimum
+
+    Ok(output)
+}
+
+// A bare SERVFAIL echoing the question, for when we can't answer safely
+pub fn create_servfail(request: &[u8]) -> NxDomainResult {
+    let question_end = question_end(request)?;
+    let mut output = Vec::from(&request[..question_end]);
     let mut cursor = Cursor::new(&mut output);
-    let response_bytes = vec![0x81, 0x83];
 
-    // We replace the flags to make it look like NXDomain
     cursor.seek(SeekFrom::Start(2))?;
+    cursor.write_all(&[0x81, 0x80 | RCODE_SERVFAIL])?;
 
-    cursor.write(&response_bytes)?;
+    // Nothing but the question
+    cursor.seek(SeekFrom::Start(6))?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+    cursor.write_u16::<NetworkEndian>(0)?;
+    cursor.write_u16::<NetworkEndian>(0)?;
 
     Ok(output)
 }
 
+pub fn type_from_name(name: &str) -> Option<u16> {
+    let name = name.trim().to_uppercase();
+    if let Some(number) = name.strip_prefix("TYPE") {
+        return number.parse().ok();
+    }
+
+    TYPE_NAMES
+        .iter()
+        .find(|(type_name, _)| *type_name == name)
+        .map(|(_, rtype)| *rtype)
+}
+
+pub fn type_name(rtype: u16) -> Option<&'static str> {
+    TYPE_NAMES
+        .iter()
+        .find(|(_, t)| *t == rtype)
+        .map(|(type_name, _)| *type_name)
+}
+
+// The type's name, or e.g. 'TYPE99' for types without one
+pub fn type_label(rtype: u16) -> String {
+    match type_name(rtype) {
+        Some(name) => name.to_string(),
+        None => format!("TYPE{}", rtype),
+    }
+}
+
+pub fn rcode_name(rcode: u8) -> Option<&'static str> {
+    RCODE_NAMES
+        .iter()
+        .find(|(_, r)| *r == rcode)
+        .map(|(name, _)| *name)
+}
+
+pub fn question_type(bytes: &[u8]) -> Result<u16> {
+    let (_, name_end) = read_name(bytes, HEADER_LENGTH)?;
+    let mut cursor = Cursor::new(bytes);
+    cursor.seek(SeekFrom::Start(name_end as u64))?;
+
+    Ok(cursor.read_u16::<NetworkEndian>()?)
+}
+
+pub fn rcode(bytes: &[u8]) -> Result<u8> {
+    match bytes.get(3) {
+        Some(b) => Ok(b & 0x0f),
+        None => Err(DnsMessageError::unexpected_read_length()),
+    }
+}
+
+pub fn is_nxdomain(bytes: &[u8]) -> Result<bool> {
+    Ok(rcode(bytes)? == RCODE_NXDOMAIN)
+}
+
+pub fn copy_id(from: &[u8], to: &mut [u8]) -> Result<()> {
+    if from.len() < 2 || to.len() < 2 {
+        return Err(DnsMessageError::unexpected_read_length());
+    }
+    to[0] = from[0];
+    to[1] = from[1];
+
+    Ok(())
+}
+
+pub fn question_bytes(bytes: &[u8]) -> Result<&[u8]> {
+    let end = question_end(bytes)?;
+
+    Ok(&bytes[HEADER_LENGTH..end])
+}
+
+// The question section with only the names lowercased, so questions that
+// differ just in letter case match. Types and classes are left alone.
+pub fn normalized_question(bytes: &[u8]) -> Result<Vec<u8>> {
+    let questions = match bytes.get(4..6) {
+        Some(count) => u16::from_be_bytes([count[0], count[1]]),
+        None => return Err(DnsMessageError::unexpected_read_length()),
+    };
+    let mut question = question_bytes(bytes)?.to_vec();
+
+    let mut position = 0;
+    for _ in 0..questions {
+        loop {
+            let length = match question.get(position) {
+                Some(l) => *l as usize,
+                None => return Err(DnsMessageError::unexpected_read_length()),
+            };
+
+            // A compression pointer ends the name
+            if length & 0xc0 == 0xc0 {
+                position += 2;
+                break;
+            }
+
+            position += 1;
+            if length == 0 {
+                break;
+            }
+
+            match question.get_mut(position..position + length) {
+                Some(label) => label.make_ascii_lowercase(),
+                None => return Err(DnsMessageError::unexpected_read_length()),
+            }
+            position += length;
+        }
+
+        // Skip over the type and class
+        position += 4;
+    }
+
+    Ok(question)
+}
+
+// Copies the question section of one message over another's, so answers
+// echo the letter case clients asked with (e.g. with 0x20 randomisation).
+// Both questions have to be the same length.
+pub fn copy_question(from: &[u8], to: &mut [u8]) -> Result<()> {
+    let question = question_bytes(from)?;
+    let end = question_end(to)?;
+    if end - HEADER_LENGTH != question.len() {
+        return Err(DnsMessageError::unexpected_read_length());
+    }
+    to[HEADER_LENGTH..end].copy_from_slice(question);
+
+    Ok(())
+}
+
+pub fn records_from_bytes(bytes: &[u8]) -> Result<Vec<ResourceRecord>> {
+    let mut cursor = Cursor::new(bytes);
+
+    // Skip the ID, flags and question count to get at the record counts
+    cursor.seek(SeekFrom::Start(6))?;
+    let answers = cursor.read_u16::<NetworkEndian>()?;
+    let authorities = cursor.read_u16::<NetworkEndian>()?;
+    let additionals = cursor.read_u16::<NetworkEndian>()?;
+
+    let sections = [
+        (Section::Answer, answers),
+        (Section::Authority, authorities),
+        (Section::Additional, additionals),
+    ];
+
+    let mut records = Vec::new();
+    let mut position = question_end(bytes)?;
+    for (section, count) in sections.iter() {
+        for _ in 0..*count {
+            let (name, name_end) = read_name(bytes, position)?;
+
+            cursor.seek(SeekFrom::Start(name_end as u64))?;
+            let rtype = cursor.read_u16::<NetworkEndian>()?;
+            let class = cursor.read_u16::<NetworkEndian>()?;
+            let ttl_offset = cursor.position() as usize;
+            let ttl = cursor.read_u32::<NetworkEndian>()?;
+            let rdata_length = cursor.read_u16::<NetworkEndian>()? as usize;
+            let rdata_offset = cursor.position() as usize;
+
+            if rdata_offset + rdata_length > bytes.len() {
+                return Err(DnsMessageError::unexpected_read_length());
+            }
+
+            records.push(ResourceRecord {
+                section: section.clone(),
+                name,
+                rtype,
+                class,
+                ttl,
+                ttl_offset,
+                rdata_offset,
+                rdata_length,
+            });
+
+            position = rdata_offset + rdata_length;
+        }
+    }
+
+    Ok(records)
+}
+
+// The addresses in the answer section's A and AAAA records
+pub fn answer_addresses(bytes: &[u8]) -> Result<Vec<IpAddr>> {
+    let mut addresses = Vec::new();
+    for record in records_from_bytes(bytes)? {
+        if record.section != Section::Answer {
+            continue;
+        }
+
+        let rdata = &bytes[record.rdata_offset..record.rdata_offset + record.rdata_length];
+        match (record.rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = rdata.try_into().unwrap();
+                addresses.push(IpAddr::from(octets));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                addresses.push(IpAddr::from(octets));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(addresses)
+}
+
+// The names CNAMEs in the answer section point at, in the order they appear
+pub fn cname_targets(bytes: &[u8]) -> Result<Vec<String>> {
+    let mut targets = Vec::new();
+    for record in records_from_bytes(bytes)? {
+        if record.section == Section::Answer && record.rtype == TYPE_CNAME {
+            let (target, _) = read_name(bytes, record.rdata_offset)?;
+            targets.push(target);
+        }
+    }
+
+    Ok(targets)
+}
+
+pub fn minimum_ttl(bytes: &[u8]) -> Result<Option<u32>> {
+    let records = records_from_bytes(bytes)?;
+
+    let ttl = records
+        .iter()
+        .filter(|r| r.section == Section::Answer && r.rtype != TYPE_OPT)
+        .map(|r| r.ttl)
+        .min();
+
+    Ok(ttl)
+}
+
+pub fn negative_ttl(bytes: &[u8]) -> Result<Option<u32>> {
+    let records = records_from_bytes(bytes)?;
+
+    let soa = match records
+        .iter()
+        .find(|r| r.section == Section::Authority && r.rtype == TYPE_SOA)
+    {
+        Some(soa) => soa,
+        None => return Ok(None),
+    };
+
+    // The SOA minimum sits after the two names and four other 32-bit fields
+    let (_, mname_end) = read_name(bytes, soa.rdata_offset)?;
+    let (_, rname_end) = read_name(bytes, mname_end)?;
+    let mut cursor = Cursor::new(bytes);
+    cursor.seek(SeekFrom::Start(rname_end as u64 + 16))?;
+    let minimum = cursor.read_u32::<NetworkEndian>()?;
+
+    // RFC 2308 says to use whichever is lower of the SOA's own TTL and its
+    // minimum field
+    Ok(Some(std::cmp::min(soa.ttl, minimum)))
+}
+
+pub fn age_ttls(bytes: &mut [u8], elapsed: u32) -> Result<()> {
+    let records = records_from_bytes(bytes)?;
+    let mut cursor = Cursor::new(bytes);
+
+    for record in records.iter().filter(|r| r.rtype != TYPE_OPT) {
+        cursor.seek(SeekFrom::Start(record.ttl_offset as u64))?;
+        cursor.write_u32::<NetworkEndian>(record.ttl.saturating_sub(elapsed))?;
+    }
+
+    Ok(())
+}
+
+pub fn set_ttls(bytes: &mut [u8], ttl: u32) -> Result<()> {
+    let records = records_from_bytes(bytes)?;
+    let mut cursor = Cursor::new(bytes);
+
+    for record in records.iter().filter(|r| r.rtype != TYPE_OPT) {
+        cursor.seek(SeekFrom::Start(record.ttl_offset as u64))?;
+        cursor.write_u32::<NetworkEndian>(ttl)?;
+    }
+
+    Ok(())
+}
+
+fn question_end(bytes: &[u8]) -> Result<usize> {
+    let mut cursor = Cursor::new(bytes);
+    cursor.seek(SeekFrom::Start(4))?;
+    let questions = cursor.read_u16::<NetworkEndian>()?;
+
+    let mut position = HEADER_LENGTH;
+    for _ in 0..questions {
+        let (_, name_end) = read_name(bytes, position)?;
+
+        // Skip over the type and class
+        position = name_end + 4;
+        if position > bytes.len() {
+            return Err(DnsMessageError::unexpected_read_length());
+        }
+    }
+
+    Ok(position)
+}
+
+fn read_name(bytes: &[u8], offset: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = match bytes.get(position) {
+            Some(l) => *l as usize,
+            None => return Err(DnsMessageError::unexpected_read_length()),
+        };
+
+        // A compression pointer; the rest of the name lives elsewhere
+        if length & 0xc0 == 0xc0 {
+            let low = match bytes.get(position + 1) {
+                Some(l) => *l as usize,
+                None => return Err(DnsMessageError::unexpected_read_length()),
+            };
+
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                return Err(DnsMessageError::invalid_pointer());
+            }
+
+            position = ((length & 0x3f) << 8) | low;
+            continue;
+        }
+
+        if length == 0 {
+            break;
+        }
+
+        let label = match bytes.get(position + 1..position + 1 + length) {
+            Some(l) => l,
+            None => return Err(DnsMessageError::unexpected_read_length()),
+        };
+        labels.push(String::from_utf8_lossy(label).to_string());
+
+        position += 1 + length;
+    }
+
+    let end = match end {
+        Some(e) => e,
+        None => position + 1,
+    };
+
+    Ok((labels.join("."), end))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -96,4 +609,137 @@ mod tests {
         let hostname = hostname_res.unwrap();
         assert_eq!(hostname, expected);
     }
+
+    fn example_response() -> Vec<u8> {
+        vec![
+            0xe4, 0x72, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65,
+            0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00,
+            0x01, 0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 0x5d,
+            0xb8, 0xd8, 0x22,
+        ]
+    }
+
+    #[test]
+    fn records_from_bytes_works() {
+        let records = records_from_bytes(&example_response()).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].section, Section::Answer);
+        assert_eq!(records[0].name, "example.com");
+        assert_eq!(records[0].rtype, 1);
+        assert_eq!(records[0].ttl, 300);
+        assert_eq!(records[0].rdata_length, 4);
+    }
+
+    #[test]
+    fn cname_targets_works() {
+        let mut response = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03, b'w',
+            b'w', b'w', 0x04, b's', b'h', b'o', b'p', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01,
+            0x00, 0x01,
+        ];
+        // www.shop.com CNAME tracker.adtech.net
+        response.extend_from_slice(&[
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x14,
+        ]);
+        response.extend_from_slice(b"\x07tracker\x06adtech\x03net\x00");
+        // tracker.adtech.net A 192.0.2.1, with the name compressed
+        response.extend_from_slice(&[
+            0xc0, 0x2a, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 192, 0, 2, 1,
+        ]);
+
+        let targets = cname_targets(&response).unwrap();
+        assert_eq!(targets, ["tracker.adtech.net"]);
+
+        let addresses = answer_addresses(&response).unwrap();
+        assert_eq!(addresses, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+
+        let records = records_from_bytes(&response).unwrap();
+        assert_eq!(records[1].name, "tracker.adtech.net");
+        assert!(cname_targets(&example_response()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn age_ttls_works() {
+        let mut response = example_response();
+        assert_eq!(minimum_ttl(&response).unwrap(), Some(300));
+
+        age_ttls(&mut response, 100).unwrap();
+        assert_eq!(minimum_ttl(&response).unwrap(), Some(200));
+
+        age_ttls(&mut response, 1000).unwrap();
+        assert_eq!(minimum_ttl(&response).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn create_nxdomain_has_soa() {
+        let msg: Vec<u8> = vec![
+            0xe4, 0x72, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x04, 0x6d,
+            0x61, 0x69, 0x6c, 0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d,
+            0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+
+        let nxdomain = create_nxdomain(&msg, 300).unwrap();
+        assert!(is_nxdomain(&nxdomain).unwrap());
+        assert_eq!(negative_ttl(&nxdomain).unwrap(), Some(300));
+
+        let records = records_from_bytes(&nxdomain).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].section, Section::Authority);
+        assert_eq!(records[0].name, "mail.google.com");
+    }
+
+    #[test]
+    fn type_from_name_works() {
+        assert_eq!(type_from_name("aaaa"), Some(TYPE_AAAA));
+        assert_eq!(type_from_name("HTTPS"), Some(65));
+        assert_eq!(type_from_name("TYPE99"), Some(99));
+        assert_eq!(type_from_name("NOPE"), None);
+        assert_eq!(type_name(TYPE_AAAA), Some("AAAA"));
+        assert_eq!(type_name(9999), None);
+        assert_eq!(type_label(9999), "TYPE9999");
+        assert_eq!(rcode_name(RCODE_NXDOMAIN), Some("NXDOMAIN"));
+        assert_eq!(rcode_name(12), None);
+    }
+
+    #[test]
+    fn create_servfail_works() {
+        let request = vec![
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x6e,
+            0x61, 0x73, 0x03, 0x6c, 0x61, 0x6e, 0x00, 0x00, 0x01, 0x00, 0x01,
+        ];
+
+        let response = create_servfail(&request).unwrap();
+        assert_eq!(rcode(&response).unwrap(), 2);
+        assert_eq!(response.len(), request.len());
+        assert!(records_from_bytes(&response).unwrap().is_empty());
+    }
+
+    #[test]
+    fn create_local_data_works() {
+        let request = vec![
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x6e,
+            0x61, 0x73, 0x03, 0x6c, 0x61, 0x6e, 0x00, 0x00, 0x01, 0x00, 0x01,
+        ];
+        let addresses: Vec<IpAddr> =
+            vec!["192.168.1.10".parse().unwrap(), "fd00::10".parse().unwrap()];
+
+        let response = create_local_data(&request, &addresses, 60).unwrap();
+        let records = records_from_bytes(&response).unwrap();
+        assert_eq!(rcode(&response).unwrap(), 0);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rtype, TYPE_A);
+        assert_eq!(
+            response[records[0].rdata_offset..],
+            [0xc0, 0xa8, 0x01, 0x0a]
+        );
+
+        // Ask for AAAA but only have an IPv4 address
+        let mut aaaa_request = request.clone();
+        aaaa_request[22] = 0x1c;
+        let response = create_local_data(&aaaa_request, &addresses[..1], 60).unwrap();
+        assert_eq!(rcode(&response).unwrap(), 0);
+        assert_eq!(negative_ttl(&response).unwrap(), Some(60));
+    }
 }
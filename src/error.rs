@@ -5,8 +5,11 @@ use std::fmt;
 pub enum BlockListErrorKind {
     Io(std::io::Error),
     Curl(curl::Error),
+    Regex(regex::Error),
     HttpNotOk,
     NoEntries,
+    VerificationFailed(String),
+    InvalidHostname(String),
 }
 
 #[derive(Debug)]
@@ -26,6 +29,14 @@ impl BlockListError {
     pub fn http_not_ok() -> Self {
         BlockListError::new(BlockListErrorKind::HttpNotOk)
     }
+
+    pub fn invalid_hostname(hostname: &str) -> Self {
+        BlockListError::new(BlockListErrorKind::InvalidHostname(hostname.to_string()))
+    }
+
+    pub fn verification_failed(reason: &str) -> Self {
+        BlockListError::new(BlockListErrorKind::VerificationFailed(reason.to_string()))
+    }
 }
 
 impl fmt::Display for BlockListError {
@@ -36,7 +47,10 @@ impl fmt::Display for BlockListError {
             Io(e) => format!("{}", e),
             NoEntries => "No block list entries".to_string(),
             Curl(e) => format!("{}", e),
+            Regex(e) => format!("{}", e),
             HttpNotOk => "Did not received HTTP 200 OK back from server".to_string(),
+            VerificationFailed(reason) => format!("Verification failed, {}", reason),
+            InvalidHostname(hostname) => format!("Invalid hostname '{}'", hostname),
         };
         write!(f, "Block list error: {}", suffix)
     }
@@ -56,12 +70,19 @@ impl From<curl::Error> for BlockListError {
     }
 }
 
+impl From<regex::Error> for BlockListError {
+    fn from(e: regex::Error) -> Self {
+        BlockListError::new(BlockListErrorKind::Regex(e))
+    }
+}
+
 #[derive(Debug)]
 pub enum DnsMessageErrorKind {
     Io(std::io::Error),
     StringEncoding(std::string::FromUtf8Error),
     TooManyQuestions,
     UnexpectedReadLength,
+    InvalidPointer,
 }
 
 #[derive(Debug)]
@@ -85,6 +106,12 @@ impl DnsMessageError {
 
         DnsMessageError::new(k)
     }
+
+    pub fn invalid_pointer() -> Self {
+        let k = DnsMessageErrorKind::InvalidPointer;
+
+        DnsMessageError::new(k)
+    }
 }
 
 impl fmt::Display for DnsMessageError {
@@ -96,6 +123,7 @@ impl fmt::Display for DnsMessageError {
             StringEncoding(e) => format!("{}", e),
             TooManyQuestions => "Too many DNS questions in request".to_string(),
             UnexpectedReadLength => "Read an unexpected amount of data".to_string(),
+            InvalidPointer => "Invalid name compression pointer".to_string(),
         };
         write!(f, "DNS Message Parsing Error: {}", suffix)
     }
@@ -197,6 +225,18 @@ impl DoTError {
         use DoTErrorKind::*;
         DoTError::new(MessageTooLarge)
     }
+
+    // A short name for the kind of error, e.g. for metric labels
+    pub fn kind_name(&self) -> &'static str {
+        use DoTErrorKind::*;
+        match self.kind {
+            NoAvailableServers => "no_available_servers",
+            Tls(_) => "tls",
+            TlsHandshake(_) => "tls_handshake",
+            Io(_) => "io",
+            MessageTooLarge => "message_too_large",
+        }
+    }
 }
 
 impl fmt::Display for DoTError {
@@ -1,114 +1,354 @@
+use chrono::Local;
 use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::sync::{atomic, Arc, RwLock};
+use std::sync::{atomic, Arc, Mutex, PoisonError, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use crate::block_list::BlockLists;
-use crate::config::Config;
+use crate::block_list::{Action, BlockLists, Selection};
+use crate::cache::Cache;
+use crate::clients::ClientGroups;
+use crate::config::{Config, DnsServer};
+use crate::control::{self, Control};
 use crate::dns_message;
+use crate::metrics;
+use crate::query_log::{Decision, QueryLog, Record, Writer};
+use crate::rebind::RebindProtection;
 use crate::tls_connection;
 use crate::tls_message;
 
+// How long clients may cache the NXDOMAIN we hand back for blocked domains
+const DEFAULT_BLOCKED_TTL: u32 = 300;
+
+// How often (in minutes) the cache is written out if a snapshot is configured
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 15;
+
+// How often expired entries are cleared out and the cache stats logged
+const CACHE_HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(600);
+
+// What to do with queries while no block list has loaded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailMode {
+    // Let them through unblocked
+    Open,
+    // Answer SERVFAIL
+    Closed,
+    // Block every name
+    Block,
+}
+
 #[derive(Debug)]
 pub struct Listener {
     config: Config,
-    block_lists: Arc<RwLock<Option<BlockLists>>>,
+    // Held just long enough to clone or swap the Arc, so a refresh never
+    // stops queries from being checked against the lists
+    block_lists: Arc<RwLock<Option<Arc<BlockLists>>>>,
+    // Held for the whole of a refresh, so refreshes from the reload thread
+    // and the control API take turns rather than undoing each other
+    refresh_lock: Arc<Mutex<()>>,
+    cache: Option<Arc<Cache>>,
+    rebind: Option<Arc<RebindProtection>>,
+    groups: Option<Arc<ClientGroups>>,
+    query_log: Option<Arc<QueryLog>>,
+    fail_mode: FailMode,
     should_stop: Arc<atomic::AtomicBool>,
     reload_thread: Option<thread::JoinHandle<()>>,
+    cache_thread: Option<thread::JoinHandle<()>>,
+    control_thread: Option<thread::JoinHandle<()>>,
+    query_log_thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Listener {
     pub fn from_config(config: &Config) -> Listener {
         let c = config.clone();
         let block_lists = Arc::new(RwLock::new(None));
+        let cache = config
+            .cache
+            .as_ref()
+            .map(|cache_config| Arc::new(Cache::from_config(cache_config)));
+
+        // Warm the cache back up from the last run
+        if let Some(cache) = &cache {
+            if cache.has_snapshot() {
+                match cache.load_snapshot() {
+                    Ok(n) => info!("Loaded {} entries from the cache snapshot", n),
+                    Err(e) => warn!("Couldn't load cache snapshot: {}", e),
+                }
+            }
+        }
+        let rebind = config
+            .rebind_protection
+            .as_ref()
+            .map(|rebind_config| Arc::new(RebindProtection::from_config(rebind_config)));
+        let groups = if config.client_group.is_empty() {
+            None
+        } else {
+            Some(Arc::new(ClientGroups::from_config(&config.client_group)))
+        };
+
         let l = Listener {
             config: c,
             block_lists: block_lists,
+            refresh_lock: Arc::new(Mutex::new(())),
+            cache,
+            rebind,
+            groups,
+            query_log: None,
+            fail_mode: FailMode::Open,
             should_stop: Arc::new(atomic::AtomicBool::new(false)),
             reload_thread: None,
+            cache_thread: None,
+            control_thread: None,
+            query_log_thread: None,
         };
 
         l
     }
 
     pub fn start_reload_thread(&mut self) {
-        // Check to make sure we should run a reload thread
-        let block_lists = match &self.config.block_lists {
-            Some(bl) => bl,
-            None => return,
+        // Check to make sure any list is going to need refreshing
+        let next_refresh = match self.block_lists.read() {
+            Ok(bl_option) => bl_option.as_ref().and_then(|bl| bl.next_refresh()),
+            Err(_) => None,
         };
-        let refresh_after = match block_lists.refresh_after {
-            Some(ra) => ra,
-            None => return,
-        };
-
-        if refresh_after == 0 {
+        if next_refresh.is_none() {
             return;
         }
 
         let should_stop = self.should_stop.clone();
-        let interval_seconds = refresh_after * 60;
         let block_lists = Arc::clone(&self.block_lists);
+        let refresh_lock = Arc::clone(&self.refresh_lock);
 
-        info!("Will reload block lists every {} minutes", refresh_after);
+        info!("Will refresh block lists as they become due");
 
-        // Okay, we have a proper refresh after
         let t = thread::spawn(move || {
-            let mut current_instant = Instant::now();
+            // When to try again after a refresh that failed outright, since
+            // the lists in use still say they're due
+            let mut retry_at: Option<Instant> = None;
             loop {
                 // Check if we should stop this thread
                 if should_stop.load(atomic::Ordering::Relaxed) {
                     break;
                 }
 
-                if current_instant.elapsed().as_secs() > interval_seconds {
-                    let mut bl_option = match block_lists.write() {
-                        Ok(bl_option) => bl_option,
-                        Err(_) => {
-                            current_instant = Instant::now();
-                            continue;
-                        }
-                    };
+                let refreshing = refresh_lock.lock().unwrap_or_else(PoisonError::into_inner);
+                let current = match block_lists.read() {
+                    Ok(bl_option) => bl_option.clone(),
+                    Err(_) => None,
+                };
+                let now = Instant::now();
+                let due = match current.as_ref().and_then(|bl| bl.next_refresh()) {
+                    Some(next) => next <= now && retry_at.is_none_or(|at| at <= now),
+                    None => false,
+                };
 
-                    let bl = match &mut *bl_option {
-                        Some(bl) => bl,
-                        None => {
-                            std::mem::drop(bl_option);
-                            current_instant = Instant::now();
-                            continue;
+                if due {
+                    // Do the refresh on a copy so queries keep using the old
+                    // lists until the new ones are ready
+                    let mut bl = (*current.unwrap()).clone();
+
+                    match bl.reload_due_lists() {
+                        Ok(_) => {
+                            info!("Reloaded block lists successfully");
+                            retry_at = None;
+                            match block_lists.write() {
+                                Ok(mut bl_option) => *bl_option = Some(Arc::new(bl)),
+                                Err(_) => warn!("Couldn't swap in the refreshed block lists"),
+                            }
                         }
+                        Err(e) => {
+                            warn!("Couldn't refresh block lists: {}", e);
+                            retry_at = bl.next_refresh();
+                        }
+                    }
+                }
+                drop(refreshing);
+                thread::sleep(Duration::from_secs(1));
+            }
+            info!("Stopping block list update thread");
+        });
+
+        self.reload_thread = Some(t);
+    }
+
+    pub fn start_cache_thread(&mut self) {
+        let cache = match &self.cache {
+            Some(c) => Arc::clone(c),
+            None => return,
+        };
+        let snapshot_interval = match &self.config.cache {
+            Some(c) => c.snapshot_interval.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL),
+            None => return,
+        };
+
+        let should_stop = self.should_stop.clone();
+        let config = self.config.clone();
+        let snapshot_seconds = snapshot_interval * 60;
+
+        if cache.prefetch_enabled() {
+            info!("Will prefetch popular cache entries before they expire");
+        }
+        if cache.has_snapshot() && snapshot_interval > 0 {
+            info!(
+                "Will snapshot the cache every {} minutes",
+                snapshot_interval
+            );
+        }
+
+        let t = thread::spawn(move || {
+            let mut snapshot_instant = Instant::now();
+            let mut housekeeping_instant = Instant::now();
+            loop {
+                if should_stop.load(atomic::Ordering::Relaxed) {
+                    break;
+                }
+
+                // A single thread working through the prefetch candidates one
+                // at a time keeps the extra upstream load bounded
+                for request in cache.prefetch_candidates() {
+                    let serialized = match tls_message::serialize(&request) {
+                        Ok(m) => m,
+                        Err(_) => continue,
                     };
 
-                    // TODO add some proper error handling stuff here
-                    match bl.reload_lists() {
-                        Ok(_) => info!("Reloaded block lists successfully"),
-                        Err(e) => warn!("Couldn't refresh block lists: {}", e),
+                    match tls_connection::relay_message(serialized.as_slice(), &config.dns_server) {
+                        Ok((res, _)) => cache.insert(&request, &res),
+                        Err(e) => debug!("Couldn't prefetch cache entry: {}", e),
                     }
+                }
 
-                    current_instant = Instant::now();
+                if cache.has_snapshot()
+                    && snapshot_seconds > 0
+                    && snapshot_instant.elapsed().as_secs() > snapshot_seconds
+                {
+                    match cache.save_snapshot() {
+                        Ok(n) => debug!("Saved {} entries to the cache snapshot", n),
+                        Err(e) => warn!("Couldn't save cache snapshot: {}", e),
+                    }
+                    snapshot_instant = Instant::now();
+                }
 
-                    std::mem::drop(bl_option);
+                if housekeeping_instant.elapsed() > CACHE_HOUSEKEEPING_INTERVAL {
+                    let purged = cache.purge_expired();
+                    let stats = cache.stats();
+                    info!(
+                        "Cache: {} entries, {} bytes, {} hits, {} misses, {} evictions, {} expired",
+                        stats.entries,
+                        stats.bytes,
+                        stats.hits,
+                        stats.misses,
+                        stats.evictions,
+                        purged
+                    );
+                    housekeeping_instant = Instant::now();
                 }
+
                 thread::sleep(Duration::from_secs(1));
             }
-            info!("Stopping block list update thread");
+            info!("Stopping cache thread");
         });
 
-        self.reload_thread = Some(t);
+        self.cache_thread = Some(t);
+    }
+
+    pub fn start_control_thread(&mut self) {
+        let control_config = match &self.config.control {
+            Some(c) => c,
+            None => return,
+        };
+        let host = control_config
+            .host
+            .as_deref()
+            .unwrap_or(control::DEFAULT_HOST);
+        let conn_string = format!("{}:{}", host, control_config.port);
+        let server = match tiny_http::Server::http(&conn_string) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Couldn't start the control API on {}: {}", conn_string, e);
+                return;
+            }
+        };
+
+        let should_stop = self.should_stop.clone();
+        let mut control = Control::new(
+            Arc::clone(&self.block_lists),
+            self.cache.clone(),
+            self.groups.clone(),
+            self.config.dns_server.clone(),
+            Arc::clone(&self.refresh_lock),
+        );
+        control.set_token(control_config.token.clone());
+
+        info!("Starting control API on HTTP {}", conn_string);
+
+        let t = thread::spawn(move || {
+            control.serve(&server, &should_stop);
+            info!("Stopping control API");
+        });
+
+        self.control_thread = Some(t);
+    }
+
+    pub fn start_query_log_thread(&mut self) {
+        let log_config = match &self.config.query_log {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut writer = Writer::from_config(log_config);
+        let (query_log, receiver) = QueryLog::new();
+        let should_stop = self.should_stop.clone();
+
+        info!("Logging queries to {}", log_config.path);
+
+        let t = thread::spawn(move || {
+            writer.run(receiver, &should_stop);
+            info!("Stopping query log writer");
+        });
+
+        self.query_log = Some(Arc::new(query_log));
+        self.query_log_thread = Some(t);
     }
 
     pub fn set_blocklists(&mut self, block_lists: BlockLists) {
-        let block_lists = Arc::new(RwLock::new(Some(block_lists)));
+        let block_lists = Arc::new(RwLock::new(Some(Arc::new(block_lists))));
         self.block_lists = block_lists;
     }
 
-    pub fn _shutdown(&mut self) {
+    pub fn set_fail_mode(&mut self, fail_mode: FailMode) {
+        self.fail_mode = fail_mode;
+    }
+
+    pub fn stop_flag(&self) -> Arc<atomic::AtomicBool> {
+        Arc::clone(&self.should_stop)
+    }
+
+    pub fn shutdown(&mut self) {
         self.should_stop.store(true, atomic::Ordering::Relaxed);
 
         if let Some(t) = self.reload_thread.take() {
-            match t.join() {
-                _ => (),
+            let _ = t.join();
+        }
+
+        if let Some(t) = self.cache_thread.take() {
+            let _ = t.join();
+        }
+
+        if let Some(t) = self.control_thread.take() {
+            let _ = t.join();
+        }
+
+        if let Some(t) = self.query_log_thread.take() {
+            let _ = t.join();
+        }
+
+        // Write the cache out one last time so the next run starts warm
+        if let Some(cache) = &self.cache {
+            if cache.has_snapshot() {
+                match cache.save_snapshot() {
+                    Ok(n) => info!("Saved {} entries to the cache snapshot", n),
+                    Err(e) => warn!("Couldn't save cache snapshot: {}", e),
+                }
             }
         }
     }
@@ -165,9 +405,26 @@ impl Listener {
     fn handle_request(&self, msg: Vec<u8>, socket: UdpSocket, src: SocketAddr) {
         let c = self.config.clone();
         let block_lists = self.block_lists.clone();
+        let cache = self.cache.clone();
+        let rebind = self.rebind.clone();
+        let groups = self.groups.clone();
+        let query_log = self.query_log.clone();
+        let fail_mode = self.fail_mode;
+        let blocked_ttl = match &self.config.block_lists {
+            Some(bl) => bl.blocked_ttl.unwrap_or(DEFAULT_BLOCKED_TTL),
+            None => DEFAULT_BLOCKED_TTL,
+        };
+        let inspect_answers = match &self.config.block_lists {
+            Some(bl) => bl.inspect_answers.unwrap_or(true),
+            None => true,
+        };
 
         // Spin up a new thread to handle this from now on
         thread::spawn(move || {
+            let _active = metrics::ActiveHandler::start();
+            let started = Instant::now();
+            let received = Local::now();
+
             // Serialize the raw DNS query into one compatible with DNS-over-TLS
             let serialized = match tls_message::serialize(&msg) {
                 Ok(m) => m,
@@ -177,54 +434,290 @@ impl Listener {
                 }
             };
 
+            // Grab the current block lists. The lock is only ever held to swap
+            // lists in, so this doesn't wait on a refresh.
+            let current = match block_lists.read() {
+                Ok(optional) => optional.clone(),
+                Err(_) => None,
+            };
+            // If the type can't be read, only entries covering every type will match
+            let qtype = dns_message::question_type(&msg).unwrap_or(0);
+            metrics::query(qtype, src.ip());
+
+            let question = dns_message::hostname_from_bytes(&msg);
+
+            let log = |decision: Decision, upstream: Option<&DnsServer>, res: Option<&[u8]>| {
+                if let Some(query_log) = &query_log {
+                    query_log.log(Record {
+                        timestamp: received,
+                        client: src.ip().to_canonical(),
+                        qname: question.as_ref().cloned().unwrap_or_default(),
+                        qtype,
+                        decision,
+                        upstream: upstream.map(|s| format!("{}:{}", s.ip_address, s.port)),
+                        rcode: res.and_then(|r| dns_message::rcode(r).ok()),
+                        latency: started.elapsed(),
+                    });
+                }
+            };
+
+            // Work out which client group (if any) the query came from, and
+            // so which lists and upstreams to use
+            let group = groups.as_ref().and_then(|g| g.group_for(src.ip()));
+            if let Some(group) = group {
+                debug!("{} is in client group {}", src.ip(), group.name);
+            }
+            let lists = group.and_then(|g| g.block_lists.as_deref());
+            let selection = match &current {
+                Some(bl) => bl.select(lists, received.naive_local()),
+                None => Selection::default(),
+            };
+            let paused = current.as_ref().is_some_and(|bl| bl.is_paused());
+            let unfiltered = paused
+                || match (group, &question) {
+                    (Some(group), Ok(hostname)) => !group.blocking || group.allows(hostname),
+                    _ => false,
+                };
+            let group_servers = match (group, &question) {
+                (Some(group), Ok(hostname)) => group.servers_for(hostname),
+                _ => None,
+            };
+            // Answers from a group's own upstreams aren't cached, so they're
+            // never handed to clients outside the group
+            let (servers, cache) = match group_servers {
+                Some(servers) => (servers, None),
+                None => (c.dns_server.as_slice(), cache),
+            };
+
             // Check to see if the domain is in the block list
-            let mut should_block = false;
-            match dns_message::hostname_from_bytes(&msg) {
-                Ok(hostname) => {
-                    // Get a read-only handle to the block lists and check them. If we
-                    // can't get the handle because the block lists are being updated just
-                    // nope out and let the request pass unblocked
-                    match block_lists.try_read() {
-                        Ok(optional) => match &*optional {
-                            Some(bl) => {
-                                debug!("Blocking domain: {}", hostname);
-                                should_block = bl.is_blocked(&hostname);
-                            }
-                            None => debug!("Not blocking domain: {}", hostname),
-                        },
-                        Err(_) => (),
+            let mut action: Option<Action> = None;
+            let mut blocked_by: Option<String> = None;
+            match &question {
+                Ok(hostname) => match &current {
+                    _ if unfiltered => debug!("Not filtering domain: {}", hostname),
+                    // Failing open still applies whatever has loaded
+                    Some(bl) if bl.is_loaded() || fail_mode == FailMode::Open => {
+                        if let Some((list, entry)) = bl.explain(hostname, qtype, &selection) {
+                            debug!("Blocking domain: {}", hostname);
+                            metrics::blocked(list.id());
+                            action = Some(entry.action.clone());
+                            blocked_by = Some(list.id().to_string());
+                        }
                     }
-                }
+                    _ => match fail_mode {
+                        FailMode::Open => debug!("Not blocking domain: {}", hostname),
+                        FailMode::Closed => {
+                            debug!("No block lists loaded, refusing: {}", hostname);
+                            metrics::blocked("fail_mode");
+                            match dns_message::create_servfail(&msg) {
+                                Ok(res) => {
+                                    if let Err(e) = socket.send_to(res.as_slice(), src) {
+                                        warn!("Error sending response: {}", e);
+                                    }
+                                    log(Decision::Refused, None, Some(&res));
+                                }
+                                Err(_) => warn!("Could not create a SERVFAIL message!"),
+                            }
+                            return;
+                        }
+                        FailMode::Block => {
+                            debug!("No block lists loaded, blocking: {}", hostname);
+                            metrics::blocked("fail_mode");
+                            action = Some(Action::Nxdomain);
+                            blocked_by = Some("fail_mode".to_string());
+                        }
+                    },
+                },
                 Err(_) => {
                     warn!("Could not extract hostname from DNS(?) message!");
                 }
             }
 
-            let res = match should_block {
-                true => match dns_message::create_nxdomain(&msg) {
-                    Ok(r) => r,
-                    Err(_) => {
-                        warn!("Could not create a NX domain message!");
-                        return;
+            // Answer from the cache if we've seen this question recently
+            let cached = match (&cache, &action) {
+                (Some(cache), None) => cache.get(&msg),
+                _ => None,
+            };
+
+            // Block answers that lead to somewhere blocked, or that point a
+            // public name at our own network. Also gives back what blocked
+            // the answer, if anything did.
+            let screen = |res: Vec<u8>| {
+                if let (Some(rebind), Ok(hostname)) = (&rebind, &question) {
+                    if let Some(address) = rebind.check(hostname, &res, group) {
+                        warn!(
+                            "Possible DNS rebinding, {} resolved to {}",
+                            hostname, address
+                        );
+                        metrics::blocked("rebind_protection");
+                        let res = dns_message::create_nxdomain(&msg, blocked_ttl).unwrap_or(res);
+                        return (res, Some("rebind_protection".to_string()));
                     }
-                },
-                false => match tls_connection::relay_message(serialized.as_slice(), &c) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        warn!("TLS Error: {}", e);
-                        return;
+                }
+
+                match &current {
+                    Some(bl) if !unfiltered => screen_answer(
+                        &msg,
+                        res,
+                        bl,
+                        qtype,
+                        &selection,
+                        blocked_ttl,
+                        inspect_answers,
+                    ),
+                    _ => (res, None),
+                }
+            };
+
+            let (res, decision, upstream) = match (&action, cached) {
+                (Some(action), _) => match block_response(&msg, action, blocked_ttl) {
+                    Some(r) => {
+                        let list = blocked_by.unwrap_or_default();
+                        let decision = match action {
+                            Action::LocalData(_) => Decision::Local(list),
+                            _ => Decision::Blocked(list),
+                        };
+                        (r, decision, None)
                     }
+                    None => return,
                 },
+                (None, Some(cached)) => {
+                    let (res, blocked) = screen(cached);
+                    (
+                        res,
+                        blocked.map_or(Decision::Cached, Decision::Blocked),
+                        None,
+                    )
+                }
+                (None, None) => {
+                    // If the upstreams were unreachable a moment ago, don't make the
+                    // client wait on them again. Answer from stale data and then try
+                    // to refresh it
+                    if let Some(cache_ref) = &cache {
+                        if cache_ref.upstream_recently_failed() {
+                            if let Some(stale) = cache_ref.get_stale(&msg) {
+                                debug!("Serving stale answer while upstreams are down");
+                                let (stale, blocked) = screen(stale);
+                                if let Err(e) = socket.send_to(stale.as_slice(), src) {
+                                    warn!("Error sending response: {}", e);
+                                }
+                                let decision = blocked.map_or(Decision::Stale, Decision::Blocked);
+                                log(decision, None, Some(&stale));
+                                relay_and_cache(&serialized, &msg, servers, &cache);
+                                return;
+                            }
+                        }
+                    }
+
+                    match relay_and_cache(&serialized, &msg, servers, &cache) {
+                        Some((res, upstream)) => {
+                            let (res, blocked) = screen(res);
+                            let decision = match (blocked, upstream) {
+                                (Some(list), _) => Decision::Blocked(list),
+                                (None, Some(_)) => Decision::Allowed,
+                                (None, None) => Decision::Stale,
+                            };
+                            (res, decision, upstream)
+                        }
+                        None => {
+                            log(Decision::Failed, None, None);
+                            return;
+                        }
+                    }
+                }
             };
 
             // Send the response back to the client
-            match socket.send_to(res.as_slice(), &src) {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!("Error sending response: {}", e);
-                    return;
-                }
+            if let Err(e) = socket.send_to(res.as_slice(), src) {
+                warn!("Error sending response: {}", e);
             }
+            log(decision, upstream, Some(&res));
         });
     }
 }
+
+// Returns the answer along with the upstream that gave it, or no upstream
+// if every one failed and a stale answer is being served instead
+fn relay_and_cache<'a>(
+    serialized: &[u8],
+    msg: &[u8],
+    servers: &'a [DnsServer],
+    cache: &Option<Arc<Cache>>,
+) -> Option<(Vec<u8>, Option<&'a DnsServer>)> {
+    match tls_connection::relay_message(serialized, servers) {
+        Ok((res, server)) => {
+            if let Some(cache) = cache {
+                cache.upstream_recovered();
+                cache.insert(msg, &res);
+            }
+            Some((res, Some(server)))
+        }
+        Err(e) => {
+            warn!("TLS Error: {}", e);
+
+            // Every upstream failed, so fall back on an expired answer if
+            // we still have one (RFC 8767)
+            let cache = cache.as_ref()?;
+            cache.upstream_failed();
+            let stale = cache.get_stale(msg)?;
+            info!("All upstreams failed, serving a stale answer");
+            Some((stale, None))
+        }
+    }
+}
+
+// Swaps an answer for a block response if it has an address from an IP
+// block list in it, or (when inspecting answers) a CNAME pointing at a
+// blocked name. The answer is cached as it came, so it's checked against
+// whatever the lists are at the time it's served. Also gives back the id
+// of the list that blocked it, if one did.
+fn screen_answer(
+    msg: &[u8],
+    res: Vec<u8>,
+    block_lists: &BlockLists,
+    qtype: u16,
+    selection: &Selection,
+    ttl: u32,
+    inspect_answers: bool,
+) -> (Vec<u8>, Option<String>) {
+    if let Some((address, list, action)) = block_lists.lookup_addresses(&res, selection) {
+        debug!("Blocking answer containing: {}", address);
+        metrics::blocked(list.id());
+        return match block_response(msg, action, ttl) {
+            Some(blocked) => (blocked, Some(list.id().to_string())),
+            None => (res, None),
+        };
+    }
+
+    if !inspect_answers {
+        return (res, None);
+    }
+
+    match block_lists.lookup_answer(&res, qtype, selection) {
+        Some((target, list, action)) => {
+            debug!("Blocking answer that leads to: {}", target);
+            metrics::blocked(list.id());
+            match block_response(msg, action, ttl) {
+                Some(blocked) => (blocked, Some(list.id().to_string())),
+                None => (res, None),
+            }
+        }
+        None => (res, None),
+    }
+}
+
+fn block_response(msg: &[u8], action: &Action, ttl: u32) -> Option<Vec<u8>> {
+    let res = match action {
+        Action::Nxdomain => dns_message::create_nxdomain(msg, ttl),
+        Action::Nodata => dns_message::create_nodata(msg, ttl),
+        Action::LocalData(addresses) => dns_message::create_local_data(msg, addresses, ttl),
+    };
+
+    match res {
+        Ok(r) => Some(r),
+        Err(_) => {
+            warn!("Could not create a block response message!");
+            None
+        }
+    }
+}
@@ -1,28 +1,52 @@
 #[macro_use]
 extern crate log;
+extern crate chrono;
 extern crate env_logger;
+extern crate flate2;
 #[macro_use]
 extern crate lazy_static;
+extern crate ctrlc;
 extern crate curl;
+extern crate httpdate;
+extern crate idna;
+extern crate ipnet;
+extern crate minisign_verify;
 extern crate native_tls;
 extern crate rand;
 extern crate regex;
 extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate tiny_http;
 extern crate toml;
+extern crate xz2;
+extern crate zstd;
 
 mod block_list;
+mod cache;
+mod clients;
 mod config;
+mod control;
 mod dns_message;
 mod error;
 mod listener;
+mod metrics;
+mod pause;
+mod query_log;
+mod rebind;
+mod schedule;
 mod tls_connection;
 mod tls_message;
 
-use block_list::{BlockListFormat, BlockLists};
+use block_list::{BlockListFormat, BlockListKind, BlockListOptions, BlockLists, Entry};
 use config::Config;
-use listener::Listener;
+use listener::{FailMode, Listener};
+use schedule::Schedule;
 use std::env;
 use std::process::exit;
+use std::sync::atomic;
+use std::thread;
+use std::time::Instant;
 
 fn main() {
     env_logger::Builder::from_default_env()
@@ -54,15 +78,46 @@ fn main() {
 
     // Create a block list from the config
     let mut block_lists = BlockLists::new();
-    for entry in &config.block_list {
+    if let Some(bl) = &config.block_lists {
+        if let Some(cache_dir) = &bl.cache_dir {
+            block_lists.set_cache_dir(cache_dir);
+        }
+        block_lists.set_refresh(bl.refresh_after, bl.refresh_jitter);
+    }
+    // IP block lists are loaded and refreshed just like the others, their
+    // entries only ever match the addresses in answers
+    let lists = config
+        .block_list
+        .iter()
+        .map(|entry| (entry, false))
+        .chain(config.ip_block_list.iter().map(|entry| (entry, true)));
+    for (entry, ip_list) in lists {
         let format = match entry.format.as_str() {
+            _ if ip_list => BlockListFormat::Cidr,
             "hosts" => BlockListFormat::Hosts,
             "one-per-line" => BlockListFormat::OnePerLine,
+            "adblock" => BlockListFormat::Adblock,
+            "dnsmasq" => BlockListFormat::Dnsmasq,
+            "rpz" => BlockListFormat::Rpz,
+            "regex" => BlockListFormat::Regex,
             _ => {
                 error!("Unknown block list format: {}", entry.format);
                 exit(1);
             }
         };
+        let (qtypes, except_qtypes) = qtypes_from_config(&entry.qtypes);
+        let options = BlockListOptions {
+            name: entry.name.clone(),
+            refresh_after: entry.refresh_after,
+            sha256: entry.sha256.clone(),
+            public_key: entry.public_key.clone(),
+            signature_url: entry.signature_url.clone(),
+            max_size: entry.max_size,
+            max_entries: entry.max_entries,
+            qtypes,
+            except_qtypes,
+            schedule: schedule_from_config(&entry.schedule),
+        };
 
         if entry.list_type == "file" {
             let path = match &entry.path {
@@ -72,8 +127,9 @@ fn main() {
                     continue;
                 }
             };
-            if block_lists.add_file(&path, &format).is_err() {
-                warn!("Couldn't add file {}", path);
+            if block_lists.add_file(&path, &format, &options).is_err() {
+                warn!("Couldn't add file {}, will keep trying", path);
+                block_lists.add_pending(BlockListKind::File, path, &format, &options);
                 continue;
             }
         } else if entry.list_type == "http" {
@@ -84,28 +140,146 @@ fn main() {
                     continue;
                 }
             };
-            if block_lists.add_http(&url, &format).is_err() {
-                warn!("Couldn't add HTTP URL {}", url);
+            if block_lists.add_http(&url, &format, &options).is_err() {
+                warn!("Couldn't add HTTP URL {}, will keep trying", url);
+                block_lists.add_pending(BlockListKind::Http, url, &format, &options);
                 continue;
             }
         }
     }
 
+    // Add any rules written directly into the config. Rules that are only
+    // active some of the time get a list of their own so the schedule can
+    // be checked for the whole list at once.
+    let mut rules = Vec::new();
+    let mut scheduled_rules = Vec::new();
+    for rule in &config.block_rule {
+        let entry = match (&rule.regex, &rule.domain) {
+            (Some(regex), None) => Entry::regex(regex.clone()),
+            (None, Some(domain)) => {
+                let include_subdomains = rule.include_subdomains.unwrap_or(false);
+                Entry::domain(domain.clone(), include_subdomains)
+            }
+            _ => {
+                error!("Each block rule needs exactly one of 'regex' or 'domain'");
+                exit(1);
+            }
+        };
+        let (qtypes, except_qtypes) = qtypes_from_config(&rule.qtypes);
+        let entry = entry.for_qtypes(qtypes, except_qtypes);
+        if rule.schedule.is_empty() {
+            rules.push(entry);
+        } else {
+            scheduled_rules.push((entry, schedule_from_config(&rule.schedule)));
+        }
+    }
+    let mut rule_lists = Vec::new();
+    if !rules.is_empty() {
+        rule_lists.push((rules, BlockListOptions::default()));
+    }
+    for (entry, schedule) in scheduled_rules {
+        let options = BlockListOptions {
+            schedule,
+            ..BlockListOptions::default()
+        };
+        rule_lists.push((vec![entry], options));
+    }
+    for (entries, options) in rule_lists {
+        if let Err(e) = block_lists.add_rules(entries, &options) {
+            error!("Invalid block rule: {}", e);
+            exit(1);
+        }
+    }
+
+    // Client groups can only pick lists that have been given a name
+    for group in &config.client_group {
+        let names = group.block_lists.iter().flatten();
+        for name in names.filter(|n| *n != "default") {
+            let exists = config
+                .block_list
+                .iter()
+                .chain(config.ip_block_list.iter())
+                .any(|list| list.name.as_ref() == Some(name));
+            if !exists {
+                warn!(
+                    "Client group {} uses unknown block list {}",
+                    group.name, name
+                );
+            }
+        }
+    }
+
+    let (fail_mode, wait_for_lists) = match &config.block_lists {
+        Some(bl) => (bl.fail_mode.as_deref(), bl.wait_for_lists.unwrap_or(false)),
+        None => (None, false),
+    };
+    let fail_mode = match fail_mode {
+        None | Some("open") => FailMode::Open,
+        Some("closed") => FailMode::Closed,
+        Some("block") => FailMode::Block,
+        Some(other) => {
+            error!("Unknown fail mode: {}", other);
+            exit(1);
+        }
+    };
+
+    // Don't answer anything until there's something to block with
+    while wait_for_lists && !block_lists.is_loaded() {
+        let next_refresh = match block_lists.next_refresh() {
+            Some(n) => n,
+            None => {
+                error!("There are no block lists to wait for");
+                exit(1);
+            }
+        };
+        warn!("No block lists have loaded yet, waiting before serving");
+        thread::sleep(next_refresh.saturating_duration_since(Instant::now()));
+        if let Err(e) = block_lists.reload_due_lists() {
+            warn!("Couldn't load block lists: {}", e);
+        }
+    }
+
+    if let Some(format) = config.query_log.as_ref().and_then(|q| q.format.as_deref()) {
+        if query_log::Format::from_name(Some(format)).is_none() {
+            error!("Unknown query log format: {}", format);
+            exit(1);
+        }
+    }
+
     // Use the config to create a listener
     let mut listener = Listener::from_config(&config);
 
     // Set blocks lists
     listener.set_blocklists(block_lists);
+    listener.set_fail_mode(fail_mode);
 
     // Start up auto update thread
     listener.start_reload_thread();
 
+    // Start the cache housekeeping (prefetching, snapshots and stats)
+    listener.start_cache_thread();
+
+    // Start writing the query log, if there is one
+    listener.start_query_log_thread();
+
+    // Start the control API, if there is one
+    listener.start_control_thread();
+
+    // Stop cleanly on Ctrl-C or SIGTERM so everything gets a chance to
+    // shut down (and the cache gets saved)
+    let should_stop = listener.stop_flag();
+    let handler = move || should_stop.store(true, atomic::Ordering::Relaxed);
+    if let Err(e) = ctrlc::set_handler(handler) {
+        warn!("Couldn't install signal handler: {}", e);
+    }
+
     // Begin listening and serving
     info!(
         "Starting listener on UDP {}:{}",
         config.bind.host, config.bind.port
     );
     let res = listener.listen_and_serve();
+    listener.shutdown();
     match res {
         Ok(_) => info!("Done with no errors"),
         Err(e) => error!("Failed: {}", e),
@@ -113,3 +287,28 @@ fn main() {
 
     exit(0);
 }
+
+fn qtypes_from_config(names: &Option<Vec<String>>) -> (Vec<u16>, Vec<u16>) {
+    let names = match names {
+        Some(n) => n,
+        None => return (Vec::new(), Vec::new()),
+    };
+
+    match block_list::parse_qtypes(names.iter().map(|n| n.as_str())) {
+        Some(qtypes) => qtypes,
+        None => {
+            error!("Unknown query type in: {}", names.join(", "));
+            exit(1);
+        }
+    }
+}
+
+fn schedule_from_config(windows: &[config::Window]) -> Schedule {
+    match Schedule::from_config(windows) {
+        Some(schedule) => schedule,
+        None => {
+            error!("Invalid schedule, times should be 'HH:MM' and days e.g. 'mon' or 'weekdays'");
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::block_list::BlockLists;
+use crate::cache::Cache;
+use crate::dns_message;
+
+// Upper bounds (in seconds) of the upstream latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// How many clients get queries counted under their own address. Source
+// addresses are easy to spoof, so past this the rest are counted together
+// as 'other' rather than growing the metrics without end.
+const MAX_CLIENTS: usize = 256;
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Counts per bucket, not cumulative. The last one is everything over
+    // the largest bucket.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| value <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Everything counted since startup. Gauges that can be read straight off
+// the block lists or cache are filled in when rendering instead.
+#[derive(Debug, Default)]
+struct Metrics {
+    // By (query type, client)
+    queries: Mutex<BTreeMap<(String, String), u64>>,
+    // The clients counted under their own address
+    clients: Mutex<BTreeSet<IpAddr>>,
+    // By the list that blocked them
+    blocked: Mutex<BTreeMap<String, u64>>,
+    // By upstream address
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    // By (upstream address, error kind)
+    upstream_failures: Mutex<BTreeMap<(String, String), u64>>,
+    tls_handshakes: AtomicU64,
+    tls_handshake_failures: AtomicU64,
+    active_handlers: AtomicI64,
+    // By (list, outcome)
+    list_refreshes: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+fn increment<K: Ord>(map: &Mutex<BTreeMap<K, u64>>, key: K) {
+    if let Ok(mut map) = map.lock() {
+        *map.entry(key).or_insert(0) += 1;
+    }
+}
+
+pub fn query(qtype: u16, client: IpAddr) {
+    let qtype = qtype_label(qtype).to_string();
+    let client = match METRICS.clients.lock() {
+        Ok(mut clients) => client_label(&mut clients, client.to_canonical()),
+        Err(_) => "other".to_string(),
+    };
+    increment(&METRICS.queries, (qtype, client));
+}
+
+// Clients pick the query type, so types without a name are counted
+// together rather than giving each of the 65536 its own series
+fn qtype_label(qtype: u16) -> &'static str {
+    dns_message::type_name(qtype).unwrap_or("other")
+}
+
+fn client_label(clients: &mut BTreeSet<IpAddr>, client: IpAddr) -> String {
+    if clients.len() < MAX_CLIENTS || clients.contains(&client) {
+        clients.insert(client);
+        client.to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+// 'list' is a block list id, or what else blocked the query (e.g.
+// 'rebind_protection')
+pub fn blocked(list: &str) {
+    increment(&METRICS.blocked, list.to_string());
+}
+
+pub fn upstream_latency(upstream: &str, latency: Duration) {
+    if let Ok(mut histograms) = METRICS.upstream_latency.lock() {
+        histograms
+            .entry(upstream.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+}
+
+pub fn upstream_failure(upstream: &str, kind: &str) {
+    increment(
+        &METRICS.upstream_failures,
+        (upstream.to_string(), kind.to_string()),
+    );
+}
+
+pub fn tls_handshake(succeeded: bool) {
+    METRICS.tls_handshakes.fetch_add(1, Ordering::Relaxed);
+    if !succeeded {
+        METRICS
+            .tls_handshake_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn list_refresh(list: &str, succeeded: bool) {
+    let outcome = if succeeded { "success" } else { "failure" };
+    increment(
+        &METRICS.list_refreshes,
+        (list.to_string(), outcome.to_string()),
+    );
+}
+
+// Counts a request handler thread as active for as long as it's held
+pub struct ActiveHandler;
+
+impl ActiveHandler {
+    pub fn start() -> ActiveHandler {
+        METRICS.active_handlers.fetch_add(1, Ordering::Relaxed);
+        ActiveHandler
+    }
+}
+
+impl Drop for ActiveHandler {
+    fn drop(&mut self) {
+        METRICS.active_handlers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Writes everything out in the Prometheus text format
+pub fn render(block_lists: Option<&BlockLists>, cache: Option<&Cache>) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "queries_total", "counter", "DNS queries received");
+    if let Ok(queries) = METRICS.queries.lock() {
+        for ((qtype, client), n) in queries.iter() {
+            sample(
+                &mut out,
+                "queries_total",
+                &[("qtype", qtype), ("client", client)],
+                *n,
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "blocked_total",
+        "counter",
+        "Queries blocked, by what blocked them",
+    );
+    if let Ok(blocked) = METRICS.blocked.lock() {
+        for (list, n) in blocked.iter() {
+            sample(&mut out, "blocked_total", &[("list", list)], *n);
+        }
+    }
+
+    header(
+        &mut out,
+        "upstream_latency_seconds",
+        "histogram",
+        "How long upstreams took to answer",
+    );
+    if let Ok(histograms) = METRICS.upstream_latency.lock() {
+        for (upstream, histogram) in histograms.iter() {
+            let mut cumulative = 0;
+            for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+                cumulative += histogram.buckets[i];
+                let le = le.to_string();
+                let labels = [("upstream", upstream.as_str()), ("le", le.as_str())];
+                sample(
+                    &mut out,
+                    "upstream_latency_seconds_bucket",
+                    &labels,
+                    cumulative,
+                );
+            }
+            let labels = [("upstream", upstream.as_str()), ("le", "+Inf")];
+            sample(
+                &mut out,
+                "upstream_latency_seconds_bucket",
+                &labels,
+                histogram.count,
+            );
+            let labels = [("upstream", upstream.as_str())];
+            sample(
+                &mut out,
+                "upstream_latency_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "upstream_latency_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "upstream_failures_total",
+        "counter",
+        "Failed upstream queries, by kind of error",
+    );
+    if let Ok(failures) = METRICS.upstream_failures.lock() {
+        for ((upstream, kind), n) in failures.iter() {
+            let labels = [("upstream", upstream.as_str()), ("kind", kind.as_str())];
+            sample(&mut out, "upstream_failures_total", &labels, *n);
+        }
+    }
+
+    header(
+        &mut out,
+        "tls_handshakes_total",
+        "counter",
+        "TLS handshakes with upstreams",
+    );
+    let handshakes = METRICS.tls_handshakes.load(Ordering::Relaxed);
+    sample(&mut out, "tls_handshakes_total", &[], handshakes);
+    header(
+        &mut out,
+        "tls_handshake_failures_total",
+        "counter",
+        "TLS handshakes with upstreams that failed",
+    );
+    let failures = METRICS.tls_handshake_failures.load(Ordering::Relaxed);
+    sample(&mut out, "tls_handshake_failures_total", &[], failures);
+
+    header(
+        &mut out,
+        "active_handlers",
+        "gauge",
+        "Queries being handled right now",
+    );
+    let active = METRICS.active_handlers.load(Ordering::Relaxed);
+    sample(&mut out, "active_handlers", &[], active);
+
+    header(
+        &mut out,
+        "block_list_refreshes_total",
+        "counter",
+        "Block list refreshes, by outcome",
+    );
+    if let Ok(refreshes) = METRICS.list_refreshes.lock() {
+        for ((list, outcome), n) in refreshes.iter() {
+            let labels = [("list", list.as_str()), ("outcome", outcome.as_str())];
+            sample(&mut out, "block_list_refreshes_total", &labels, *n);
+        }
+    }
+
+    if let Some(block_lists) = block_lists {
+        header(
+            &mut out,
+            "block_list_entries",
+            "gauge",
+            "Entries in each block list",
+        );
+        for list in &block_lists.lists {
+            let labels = [("list", list.id())];
+            sample(&mut out, "block_list_entries", &labels, list.entries.len());
+        }
+    }
+
+    if let Some(cache) = cache {
+        let stats = cache.stats();
+        header(
+            &mut out,
+            "cache_hits_total",
+            "counter",
+            "Queries answered from the cache",
+        );
+        sample(&mut out, "cache_hits_total", &[], stats.hits);
+        header(
+            &mut out,
+            "cache_misses_total",
+            "counter",
+            "Queries not in the cache",
+        );
+        sample(&mut out, "cache_misses_total", &[], stats.misses);
+        header(
+            &mut out,
+            "cache_hit_ratio",
+            "gauge",
+            "Share of lookups answered from the cache",
+        );
+        let lookups = stats.hits + stats.misses;
+        let ratio = if lookups > 0 {
+            stats.hits as f64 / lookups as f64
+        } else {
+            0.0
+        };
+        sample(&mut out, "cache_hit_ratio", &[], ratio);
+        header(&mut out, "cache_entries", "gauge", "Answers in the cache");
+        sample(&mut out, "cache_entries", &[], stats.entries);
+        header(
+            &mut out,
+            "cache_bytes",
+            "gauge",
+            "Size of the answers in the cache",
+        );
+        sample(&mut out, "cache_bytes", &[], stats.bytes);
+        header(
+            &mut out,
+            "cache_evictions_total",
+            "counter",
+            "Answers evicted to make room for others",
+        );
+        sample(&mut out, "cache_evictions_total", &[], stats.evictions);
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP tinydnsproxy_{} {}.", name, help);
+    let _ = writeln!(out, "# TYPE tinydnsproxy_{} {}", name, kind);
+}
+
+fn sample<V: std::fmt::Display>(out: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    let _ = write!(out, "tinydnsproxy_{}", name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_work() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(30.0);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[5], 1);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(histogram.count, 3);
+    }
+
+    #[test]
+    fn clients_are_capped() {
+        let mut clients = BTreeSet::new();
+        for i in 0..MAX_CLIENTS {
+            let client = IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]);
+            assert_eq!(client_label(&mut clients, client), client.to_string());
+        }
+
+        let known = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(client_label(&mut clients, known), "10.0.0.1");
+        let new = IpAddr::from([192, 168, 1, 1]);
+        assert_eq!(client_label(&mut clients, new), "other");
+        assert_eq!(clients.len(), MAX_CLIENTS);
+    }
+
+    #[test]
+    fn query_types_are_capped() {
+        assert_eq!(qtype_label(dns_message::TYPE_AAAA), "AAAA");
+        assert_eq!(qtype_label(65), "HTTPS");
+        assert_eq!(qtype_label(97), "other");
+        assert_eq!(qtype_label(65535), "other");
+    }
+
+    #[test]
+    fn render_works() {
+        query(
+            dns_message::TYPE_AAAA,
+            "::ffff:192.168.1.20".parse().unwrap(),
+        );
+        blocked("ads \"list\"");
+        upstream_latency("192.0.2.53:853", Duration::from_millis(30));
+        {
+            let _active = ActiveHandler::start();
+            assert!(render(None, None).contains("tinydnsproxy_active_handlers 1\n"));
+        }
+
+        let out = render(None, None);
+        assert!(
+            out.contains("tinydnsproxy_queries_total{qtype=\"AAAA\",client=\"192.168.1.20\"} 1\n")
+        );
+        assert!(out.contains("tinydnsproxy_blocked_total{list=\"ads \\\"list\\\"\"} 1\n"));
+        assert!(out.contains(
+            "tinydnsproxy_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:853\",le=\"0.025\"} 0\n"
+        ));
+        assert!(out.contains(
+            "tinydnsproxy_upstream_latency_seconds_bucket{upstream=\"192.0.2.53:853\",le=\"0.05\"} 1\n"
+        ));
+        assert!(out.contains("# TYPE tinydnsproxy_upstream_latency_seconds histogram\n"));
+
+        let cache = Cache::from_config(&crate::config::Cache {
+            negative_ttl_max: None,
+            stale_window: None,
+            prefetch_hits: None,
+            snapshot_path: None,
+            snapshot_interval: None,
+            max_entries: None,
+            max_bytes: None,
+        });
+        let out = render(None, Some(&cache));
+        assert!(out.contains("tinydnsproxy_cache_bytes 0\n"));
+        assert!(out.contains("tinydnsproxy_cache_evictions_total 0\n"));
+    }
+}
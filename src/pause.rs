@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// What a pause covers
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Target {
+    // All blocking, including answer screening and fail modes
+    All,
+    // A single list, by its id (see BlockList::id)
+    List(String),
+}
+
+impl Target {
+    // Whether this is a pause of the list with the id, without building a
+    // Target to compare against
+    pub fn is_list(&self, id: &str) -> bool {
+        match self {
+            Target::All => false,
+            Target::List(list) => list == id,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::All => write!(f, "all blocking"),
+            Target::List(id) => write!(f, "block list {}", id),
+        }
+    }
+}
+
+// Blocking that has been switched off for a while. Pauses end on their
+// own once their time is up.
+#[derive(Debug, Default)]
+pub struct Pauses {
+    until: Mutex<HashMap<Target, Instant>>,
+}
+
+impl Pauses {
+    pub fn new() -> Pauses {
+        Pauses::default()
+    }
+
+    // Returns false if the pause couldn't be started, e.g. because it would
+    // run past the end of time
+    pub fn pause(&self, target: Target, duration: Duration) -> bool {
+        let end = match Instant::now().checked_add(duration) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        match self.until.lock() {
+            Ok(mut until) => {
+                info!("Pausing {} for {} minutes", target, duration.as_secs() / 60);
+                until.insert(target, end);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Returns false if the target wasn't paused
+    pub fn resume(&self, target: &Target) -> bool {
+        match self.until.lock() {
+            Ok(mut until) => {
+                let resumed = until.remove(target).is_some();
+                if resumed {
+                    info!("Resuming {}", target);
+                }
+                resumed
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_paused(&self, target: &Target) -> bool {
+        match self.until.lock() {
+            Ok(until) => until.get(target).is_some_and(|u| *u > Instant::now()),
+            Err(_) => false,
+        }
+    }
+
+    // What's paused and for how much longer
+    pub fn paused(&self) -> Vec<(Target, Duration)> {
+        let now = Instant::now();
+        match self.until.lock() {
+            Ok(until) => until
+                .iter()
+                .filter(|(_, u)| **u > now)
+                .map(|(target, u)| (target.clone(), *u - now))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Clears out pauses whose time is up, so blocking coming back on is
+    // logged when it happens
+    pub fn expire(&self) {
+        let now = Instant::now();
+        if let Ok(mut until) = self.until.lock() {
+            until.retain(|target, u| {
+                if *u <= now {
+                    info!("Pause of {} is over", target);
+                }
+                *u > now
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pauses_work() {
+        let pauses = Pauses::new();
+        let list = Target::List("ads".to_string());
+
+        assert!(pauses.pause(Target::All, Duration::from_secs(60)));
+        assert!(pauses.pause(list.clone(), Duration::from_millis(10)));
+        assert!(!pauses.pause(Target::All, Duration::MAX));
+        assert!(pauses.is_paused(&Target::All));
+        assert!(pauses.is_paused(&list));
+        assert!(!pauses.is_paused(&Target::List("other".to_string())));
+        assert!(list.is_list("ads"));
+        assert!(!Target::All.is_list("ads"));
+        assert_eq!(pauses.paused().len(), 2);
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(!pauses.is_paused(&list));
+        pauses.expire();
+        assert_eq!(pauses.paused().len(), 1);
+
+        assert!(pauses.resume(&Target::All));
+        assert!(!pauses.resume(&Target::All));
+        assert!(!pauses.is_paused(&Target::All));
+    }
+}
@@ -0,0 +1,437 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::dns_message;
+
+// How many records can wait on the writer. If it falls this far behind,
+// records are dropped rather than holding up queries.
+const QUEUE_LENGTH: usize = 4096;
+
+// Rotate once the file is this big (in bytes) unless told otherwise
+const DEFAULT_MAX_SIZE: u64 = 10485760;
+
+// How many rotated files to keep unless told otherwise
+const DEFAULT_KEEP: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // One JSON object per line
+    Json,
+    // Tab separated values, with '-' for anything missing
+    Tsv,
+}
+
+impl Format {
+    pub fn from_name(name: Option<&str>) -> Option<Format> {
+        match name {
+            None | Some("json") => Some(Format::Json),
+            Some("tsv") => Some(Format::Tsv),
+            Some(_) => None,
+        }
+    }
+}
+
+// How a query was answered
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    // Relayed to an upstream
+    Allowed,
+    // Answered from the cache
+    Cached,
+    // Answered from an expired cache entry while the upstreams were down
+    Stale,
+    // Blocked by the list with this id, or by whatever else blocked it
+    // (e.g. 'rebind_protection')
+    Blocked(String),
+    // Answered with local data from the list with this id
+    Local(String),
+    // Answered SERVFAIL as no block lists had loaded
+    Refused,
+    // Left unanswered as every upstream failed
+    Failed,
+}
+
+impl Decision {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Decision::Allowed => "allowed",
+            Decision::Cached => "cached",
+            Decision::Stale => "stale",
+            Decision::Blocked(_) => "blocked",
+            Decision::Local(_) => "local",
+            Decision::Refused => "refused",
+            Decision::Failed => "failed",
+        }
+    }
+
+    pub fn list(&self) -> Option<&str> {
+        match self {
+            Decision::Blocked(list) | Decision::Local(list) => Some(list),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub timestamp: DateTime<Local>,
+    pub client: IpAddr,
+    pub qname: String,
+    pub qtype: u16,
+    pub decision: Decision,
+    // The upstream that answered, as 'address:port'
+    pub upstream: Option<String>,
+    pub rcode: Option<u8>,
+    // How long the query took to handle, start to finish
+    pub latency: Duration,
+}
+
+impl Record {
+    pub fn to_line(&self, format: Format) -> String {
+        let timestamp = self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false);
+        let qtype = dns_message::type_label(self.qtype);
+        let rcode = self
+            .rcode
+            .map(|rcode| match dns_message::rcode_name(rcode) {
+                Some(name) => name.to_string(),
+                None => rcode.to_string(),
+            });
+        let latency_ms = self.latency.as_secs_f64() * 1000.0;
+
+        match format {
+            Format::Json => json!({
+                "timestamp": timestamp,
+                "client": self.client.to_string(),
+                "qname": self.qname,
+                "qtype": qtype,
+                "decision": self.decision.name(),
+                "list": self.decision.list(),
+                "upstream": self.upstream,
+                "rcode": rcode,
+                "latency_ms": (latency_ms * 1000.0).round() / 1000.0,
+            })
+            .to_string(),
+            Format::Tsv => {
+                let fields = [
+                    timestamp,
+                    self.client.to_string(),
+                    tsv_field(Some(&self.qname)),
+                    qtype,
+                    self.decision.name().to_string(),
+                    tsv_field(self.decision.list()),
+                    tsv_field(self.upstream.as_deref()),
+                    tsv_field(rcode.as_deref()),
+                    format!("{:.3}", latency_ms),
+                ];
+                fields.join("\t")
+            }
+        }
+    }
+}
+
+// Keeps names (which can hold almost anything) from breaking up the line
+fn tsv_field(value: Option<&str>) -> String {
+    match value {
+        Some(v) if !v.is_empty() => v.replace(['\t', '\n', '\r'], " "),
+        _ => "-".to_string(),
+    }
+}
+
+// Hands records to the writer thread, so queries never wait on the disk
+#[derive(Debug)]
+pub struct QueryLog {
+    sender: SyncSender<Record>,
+}
+
+impl QueryLog {
+    pub fn new() -> (QueryLog, Receiver<Record>) {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        (QueryLog { sender }, receiver)
+    }
+
+    pub fn log(&self, record: Record) {
+        match self.sender.try_send(record) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => debug!("Query log is behind, dropping a record"),
+            // The writer has stopped, as we're shutting down
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+// Writes records out to the log file, rotating it once it gets too big or
+// old. Rotated files get a numbered suffix, '.1' being the newest.
+#[derive(Debug)]
+pub struct Writer {
+    path: PathBuf,
+    format: Format,
+    max_size: Option<u64>,
+    rotate_after: Option<Duration>,
+    keep: usize,
+    file: Option<BufWriter<File>>,
+    size: u64,
+    opened: Option<Instant>,
+    // So a full disk is warned about once, not for every record
+    failing: bool,
+}
+
+impl Writer {
+    pub fn from_config(config: &config::QueryLog) -> Writer {
+        let max_size = config.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+        let rotate_after = config.rotate_after.unwrap_or(0);
+
+        Writer {
+            path: PathBuf::from(&config.path),
+            format: Format::from_name(config.format.as_deref()).unwrap_or(Format::Json),
+            max_size: if max_size > 0 { Some(max_size) } else { None },
+            rotate_after: if rotate_after > 0 {
+                Some(Duration::from_secs(rotate_after * 60))
+            } else {
+                None
+            },
+            keep: config.keep.unwrap_or(DEFAULT_KEEP),
+            file: None,
+            size: 0,
+            opened: None,
+            failing: false,
+        }
+    }
+
+    // Writes records as they come in until the sender goes away or we're
+    // told to stop, writing out whatever is still queued before returning
+    pub fn run(&mut self, receiver: Receiver<Record>, should_stop: &AtomicBool) {
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(record) => {
+                    self.write_or_warn(&record);
+                    // Batch up anything else waiting before flushing
+                    while let Ok(record) = receiver.try_recv() {
+                        self.write_or_warn(&record);
+                    }
+                    self.flush_or_warn();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    // Rotate on time even when it's quiet
+                    if let Err(e) = self.rotate_if_due() {
+                        warn!("Couldn't rotate the query log: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if should_stop.load(Ordering::Relaxed) {
+                while let Ok(record) = receiver.try_recv() {
+                    self.write_or_warn(&record);
+                }
+                break;
+            }
+        }
+
+        self.flush_or_warn();
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.rotate_if_due()?;
+
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let line = record.to_line(self.format);
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", line)?;
+            self.size += line.len() as u64 + 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_or_warn(&mut self, record: &Record) {
+        match self.write(record) {
+            Ok(_) => self.failing = false,
+            Err(e) => {
+                if !self.failing {
+                    warn!("Couldn't write to the query log: {}", e);
+                }
+                self.failing = true;
+            }
+        }
+    }
+
+    fn flush_or_warn(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Couldn't write to the query log: {}", e);
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.opened = Some(Instant::now());
+        self.file = Some(BufWriter::new(file));
+
+        Ok(())
+    }
+
+    fn rotate_if_due(&mut self) -> io::Result<()> {
+        // Never rotate out an empty file
+        if self.file.is_none() || self.size == 0 {
+            return Ok(());
+        }
+
+        let too_big = self.max_size.is_some_and(|max| self.size >= max);
+        let too_old = match (self.rotate_after, self.opened) {
+            (Some(after), Some(opened)) => opened.elapsed() >= after,
+            _ => false,
+        };
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        debug!("Rotating the query log");
+        let file = self.file.take();
+        self.size = 0;
+        self.opened = None;
+        if let Some(mut file) = file {
+            file.flush()?;
+        }
+
+        if self.keep == 0 {
+            return ignore_missing(fs::remove_file(&self.path));
+        }
+
+        // Shift the older files along one, dropping the oldest
+        ignore_missing(fs::remove_file(self.rotated_path(self.keep)))?;
+        for n in (1..self.keep).rev() {
+            ignore_missing(fs::rename(self.rotated_path(n), self.rotated_path(n + 1)))?;
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(decision: Decision) -> Record {
+        Record {
+            timestamp: Local::now(),
+            client: "192.168.1.20".parse().unwrap(),
+            qname: "ads.example.com".to_string(),
+            qtype: dns_message::TYPE_AAAA,
+            decision,
+            upstream: None,
+            rcode: Some(3),
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn records_are_formatted() {
+        let blocked = record(Decision::Blocked("ads".to_string()));
+        let line: serde_json::Value = serde_json::from_str(&blocked.to_line(Format::Json)).unwrap();
+        assert_eq!(line["client"], "192.168.1.20");
+        assert_eq!(line["qtype"], "AAAA");
+        assert_eq!(line["decision"], "blocked");
+        assert_eq!(line["list"], "ads");
+        assert_eq!(line["upstream"], serde_json::Value::Null);
+        assert_eq!(line["rcode"], "NXDOMAIN");
+        assert_eq!(line["latency_ms"], 1.5);
+
+        let mut allowed = record(Decision::Allowed);
+        allowed.qname = "odd\tname".to_string();
+        allowed.upstream = Some("192.0.2.53:853".to_string());
+        allowed.rcode = Some(0);
+        let line = allowed.to_line(Format::Tsv);
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert_eq!(
+            fields[1..],
+            [
+                "192.168.1.20",
+                "odd name",
+                "AAAA",
+                "allowed",
+                "-",
+                "192.0.2.53:853",
+                "NOERROR",
+                "1.500"
+            ]
+        );
+    }
+
+    #[test]
+    fn logs_are_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries.log");
+        let config = config::QueryLog {
+            path: path.to_string_lossy().to_string(),
+            format: Some("tsv".to_string()),
+            max_size: Some(1),
+            rotate_after: None,
+            keep: Some(2),
+        };
+
+        // Every record goes over the size limit, so each write after the
+        // first rotates
+        let mut writer = Writer::from_config(&config);
+        for _ in 0..4 {
+            writer.write(&record(Decision::Cached)).unwrap();
+        }
+        writer.flush().unwrap();
+        assert!(path.exists());
+        assert!(writer.rotated_path(1).exists());
+        assert!(writer.rotated_path(2).exists());
+        assert!(!writer.rotated_path(3).exists());
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+
+        // Records sent to the log are written out by the time the writer stops
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries.log");
+        let config = config::QueryLog {
+            path: path.to_string_lossy().to_string(),
+            ..config
+        };
+        let (query_log, receiver) = QueryLog::new();
+        query_log.log(record(Decision::Failed));
+        query_log.log(record(Decision::Stale));
+        drop(query_log);
+        Writer::from_config(&config).run(receiver, &AtomicBool::new(false));
+        assert!(fs::read_to_string(&path).unwrap().contains("\tstale\t"));
+    }
+}
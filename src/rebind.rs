@@ -0,0 +1,155 @@
+use std::net::IpAddr;
+
+use crate::block_list;
+use crate::clients::ClientGroup;
+use crate::config;
+use crate::dns_message;
+
+// Stops public names from resolving to addresses on our own network. We
+// only forward to public resolvers, so a public name answering with e.g.
+// 192.168.1.1 is almost always a DNS rebinding attack on a LAN device.
+#[derive(Debug)]
+pub struct RebindProtection {
+    // Domains (and their subdomains) that are allowed internal addresses
+    exempt_domains: Vec<String>,
+}
+
+impl RebindProtection {
+    pub fn from_config(config: &config::RebindProtection) -> RebindProtection {
+        let mut exempt_domains = Vec::new();
+        for domain in &config.exempt_domains {
+            match block_list::normalize_hostname(domain) {
+                Some(d) => exempt_domains.push(d),
+                None => warn!("Ignoring invalid rebind exemption '{}'", domain),
+            }
+        }
+
+        RebindProtection { exempt_domains }
+    }
+
+    // Returns the first internal address in the answer to a query for the
+    // hostname, unless the hostname is exempt. Names the client's group
+    // forwards to its own resolvers (e.g. a company domain) are expected to
+    // resolve internally, so they're exempt too.
+    pub fn check(
+        &self,
+        hostname: &str,
+        response: &[u8],
+        group: Option<&ClientGroup>,
+    ) -> Option<IpAddr> {
+        let hostname = block_list::normalize_hostname(hostname)?;
+        if self.is_exempt(&hostname) || group.is_some_and(|g| g.forward_for(&hostname).is_some()) {
+            return None;
+        }
+
+        let addresses = dns_message::answer_addresses(response).ok()?;
+        addresses.into_iter().find(is_internal)
+    }
+
+    fn is_exempt(&self, hostname: &str) -> bool {
+        self.exempt_domains
+            .iter()
+            .any(|domain| hostname == domain || block_list::is_subdomain(hostname, domain))
+    }
+}
+
+// Private, loopback, link-local, carrier-grade NAT (100.64.0.0/10) and
+// unspecified addresses, along with IPv6 unique local addresses and IPv4
+// addresses mapped into IPv6
+fn is_internal(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(a) => {
+            let octets = a.octets();
+            a.is_private()
+                || a.is_loopback()
+                || a.is_link_local()
+                || a.is_unspecified()
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(a) => {
+            if let Some(mapped) = a.to_ipv4_mapped() {
+                return is_internal(&IpAddr::V4(mapped));
+            }
+
+            let first = a.segments()[0];
+            a.is_loopback()
+                || a.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::ClientGroups;
+
+    fn response_with(address: [u8; 4]) -> Vec<u8> {
+        let mut response = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, b'e',
+            b'v', b'i', b'l', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c,
+            0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04,
+        ];
+        response.extend_from_slice(&address);
+        response
+    }
+
+    #[test]
+    fn internal_answers_are_caught() {
+        let config = config::RebindProtection {
+            exempt_domains: vec!["plex.direct".to_string()],
+        };
+        let protection = RebindProtection::from_config(&config);
+
+        let private = response_with([192, 168, 1, 1]);
+        let public = response_with([93, 184, 216, 34]);
+
+        assert_eq!(
+            protection.check("evil.com", &private, None),
+            Some("192.168.1.1".parse().unwrap())
+        );
+        assert!(protection.check("evil.com", &public, None).is_none());
+        assert!(protection
+            .check("abc.plex.direct", &private, None)
+            .is_none());
+        assert!(protection.check("notplex.direct", &private, None).is_some());
+
+        assert!(is_internal(&"100.64.0.1".parse().unwrap()));
+        assert!(is_internal(&"100.127.255.254".parse().unwrap()));
+        assert!(!is_internal(&"100.128.0.1".parse().unwrap()));
+        assert!(is_internal(&"fe80::1".parse().unwrap()));
+        assert!(is_internal(&"fd12:3456::1".parse().unwrap()));
+        assert!(is_internal(&"::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_internal(&"2606:4700::1111".parse().unwrap()));
+    }
+
+    #[test]
+    fn forwarded_domains_are_exempt() {
+        let protection = RebindProtection::from_config(&config::RebindProtection {
+            exempt_domains: Vec::new(),
+        });
+        let groups = ClientGroups::from_config(&[config::ClientGroup {
+            name: "office".to_string(),
+            clients: vec!["192.168.1.0/24".to_string()],
+            block_lists: None,
+            allow: Vec::new(),
+            blocking: None,
+            dns_server: Vec::new(),
+            forward: vec![config::Forward {
+                domain: "evil.com".to_string(),
+                dns_server: vec![config::DnsServer {
+                    ip_address: "10.0.0.53".to_string(),
+                    port: 853,
+                    hostname: "dns.evil.com".to_string(),
+                }],
+            }],
+        }]);
+        let group = groups.group_for("192.168.1.10".parse().unwrap());
+        assert!(group.is_some());
+
+        let private = response_with([192, 168, 1, 1]);
+        assert!(protection.check("evil.com", &private, group).is_none());
+        assert!(protection.check("evil.com", &private, None).is_some());
+    }
+}
@@ -0,0 +1,135 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+
+use crate::config;
+
+// When a block list or rule is in force. A schedule with no windows is
+// always active.
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+#[derive(Clone, Debug)]
+struct Window {
+    // The days the window starts on, empty for every day
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    until: NaiveTime,
+}
+
+impl Schedule {
+    // Returns None if any of the days or times can't be read
+    pub fn from_config(windows: &[config::Window]) -> Option<Schedule> {
+        let mut parsed = Vec::new();
+        for window in windows {
+            let mut days = Vec::new();
+            for day in &window.days {
+                days.extend(parse_days(day)?);
+            }
+
+            parsed.push(Window {
+                days,
+                from: parse_time(&window.from)?,
+                until: parse_time(&window.until)?,
+            });
+        }
+
+        Some(Schedule { windows: parsed })
+    }
+
+    fn is_always(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    // Schedules are in local time, so the time given should be too to
+    // follow the machine's clock through daylight saving changes
+    pub fn is_active(&self, at: NaiveDateTime) -> bool {
+        if self.is_always() {
+            return true;
+        }
+
+        let time = at.time();
+        let today = at.weekday();
+        self.windows.iter().any(|w| {
+            let starts_on = |day: Weekday| w.days.is_empty() || w.days.contains(&day);
+            if w.from < w.until {
+                starts_on(today) && time >= w.from && time < w.until
+            } else if w.from > w.until {
+                // Windows that run past midnight, e.g. 22:00 to 07:00,
+                // belong to the day they start on
+                (starts_on(today) && time >= w.from) || (starts_on(today.pred()) && time < w.until)
+            } else {
+                // The same start and end time covers the whole day
+                starts_on(today)
+            }
+        })
+    }
+}
+
+// Days are written as e.g. 'mon' or 'monday', along with 'weekdays' and
+// 'weekends'
+fn parse_days(day: &str) -> Option<Vec<Weekday>> {
+    use Weekday::*;
+
+    match day.to_lowercase().as_str() {
+        "weekdays" => Some(vec![Mon, Tue, Wed, Thu, Fri]),
+        "weekends" => Some(vec![Sat, Sun]),
+        other => other.parse().ok().map(|d| vec![d]),
+    }
+}
+
+// Times are 24 hour 'HH:MM', with '24:00' for the end of the day
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    if time == "24:00" {
+        return NaiveTime::from_hms_opt(0, 0, 0);
+    }
+
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // 2024-01-01 was a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(parse_time(time).unwrap())
+    }
+
+    fn window(days: &[&str], from: &str, until: &str) -> config::Window {
+        config::Window {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            from: from.to_string(),
+            until: until.to_string(),
+        }
+    }
+
+    #[test]
+    fn schedules_work() {
+        let bedtime = Schedule::from_config(&[window(&["weekdays"], "22:00", "07:00")]).unwrap();
+        assert!(bedtime.is_active(at(1, "22:30")));
+        assert!(bedtime.is_active(at(2, "06:59")));
+        assert!(!bedtime.is_active(at(2, "07:00")));
+        assert!(!bedtime.is_active(at(1, "21:59")));
+        // Friday night runs into Saturday morning, but Saturday night is off
+        assert!(bedtime.is_active(at(6, "03:00")));
+        assert!(!bedtime.is_active(at(6, "23:00")));
+        // Sunday night isn't in the window, so Monday morning isn't either
+        assert!(!bedtime.is_active(at(1, "03:00")));
+
+        let lunch = Schedule::from_config(&[window(&[], "12:00", "13:00")]).unwrap();
+        assert!(lunch.is_active(at(7, "12:30")));
+        assert!(!lunch.is_active(at(7, "13:30")));
+
+        let weekend = Schedule::from_config(&[window(&["sat", "Sunday"], "00:00", "00:00")]);
+        assert!(weekend.as_ref().unwrap().is_active(at(7, "09:00")));
+        assert!(!weekend.unwrap().is_active(at(5, "09:00")));
+
+        assert!(Schedule::default().is_active(at(1, "12:00")));
+        assert!(Schedule::from_config(&[window(&["someday"], "12:00", "13:00")]).is_none());
+        assert!(Schedule::from_config(&[window(&[], "noon", "13:00")]).is_none());
+    }
+}
@@ -1,31 +1,144 @@
-use std::io::{Cursor, Read, Write};
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::config::Config;
+use crate::config::DnsServer;
 use crate::error::DoTError;
+use crate::metrics;
 use byteorder::{NetworkEndian, ReadBytesExt};
 use native_tls::TlsConnector;
 use rand::seq::SliceRandom;
 
 type Result<T> = std::result::Result<T, DoTError>;
 
-pub fn relay_message(msg: &[u8], conf: &Config) -> Result<Vec<u8>> {
-    // Choose a random upstream DNS resolver to connect to
-    let server = match conf.dns_server.choose(&mut rand::thread_rng()) {
-        Some(s) => s,
-        None => {
-            let e = DoTError::no_available_servers();
-            return Err(e);
+// Clients usually give up on us after a few seconds, so don't let a single
+// unresponsive upstream eat all of that
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // Keyed by each upstream's address and port
+    static ref HEALTH: Mutex<HashMap<String, UpstreamHealth>> = Mutex::new(HashMap::new());
+}
+
+// How an upstream has been getting on since we started
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamHealth {
+    pub hostname: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_latency: Option<Duration>,
+    pub last_error: Option<String>,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+}
+
+impl UpstreamHealth {
+    // Whether the last query sent to the upstream was answered
+    pub fn is_healthy(&self) -> bool {
+        match (self.last_success, self.last_failure) {
+            (Some(success), Some(failure)) => success > failure,
+            (_, None) => true,
+            (None, Some(_)) => false,
         }
+    }
+}
+
+// Every upstream that's been tried, sorted by address
+pub fn upstream_health() -> Vec<(String, UpstreamHealth)> {
+    let mut health: Vec<(String, UpstreamHealth)> = match HEALTH.lock() {
+        Ok(h) => h.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        Err(_) => Vec::new(),
     };
-    let conn_string = format!("{}:{}", server.ip_address, server.port);
+    health.sort_by(|a, b| a.0.cmp(&b.0));
+    health
+}
+
+fn record_outcome(server: &DnsServer, started: Instant, result: &Result<Vec<u8>>) {
+    let mut health = match HEALTH.lock() {
+        Ok(h) => h,
+        Err(_) => return,
+    };
+
+    let key = format!("{}:{}", server.ip_address, server.port);
+    match result {
+        Ok(_) => metrics::upstream_latency(&key, started.elapsed()),
+        Err(e) => metrics::upstream_failure(&key, e.kind_name()),
+    }
+    let upstream = health.entry(key).or_default();
+    upstream.hostname = server.hostname.clone();
+    match result {
+        Ok(_) => {
+            upstream.successes += 1;
+            upstream.last_latency = Some(started.elapsed());
+            upstream.last_success = Some(SystemTime::now());
+        }
+        Err(e) => {
+            upstream.failures += 1;
+            upstream.last_error = Some(e.to_string());
+            upstream.last_failure = Some(SystemTime::now());
+        }
+    }
+}
+
+// Returns the response along with the upstream that gave it
+pub fn relay_message<'a>(msg: &[u8], servers: &'a [DnsServer]) -> Result<(Vec<u8>, &'a DnsServer)> {
+    // Try the upstream DNS resolvers in a random order until one answers
+    let mut servers: Vec<&DnsServer> = servers.iter().collect();
+    servers.shuffle(&mut rand::thread_rng());
+
+    let mut last_error = DoTError::no_available_servers();
+    for server in servers {
+        let started = Instant::now();
+        let result = relay_to_server(msg, server);
+        record_outcome(server, started, &result);
+        match result {
+            Ok(response) => return Ok((response, server)),
+            Err(e) => {
+                debug!("Upstream {} failed: {}", server.ip_address, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+// The addresses to try for an upstream. 'ip_address' can be an IPv4 or
+// IPv6 address (with or without brackets) or a hostname to look up.
+fn upstream_addresses(server: &DnsServer) -> io::Result<Vec<SocketAddr>> {
+    let host = server
+        .ip_address
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    Ok((host, server.port).to_socket_addrs()?.collect())
+}
+
+fn connect(server: &DnsServer) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "Bad upstream address");
+    for address in upstream_addresses(server)? {
+        match TcpStream::connect_timeout(&address, UPSTREAM_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
 
+    Err(last_error)
+}
+
+fn relay_to_server(msg: &[u8], server: &DnsServer) -> Result<Vec<u8>> {
     // Create a new TLS connector and TCP stream and glue them together
     let connector = TlsConnector::new()?;
 
-    let stream = TcpStream::connect(conn_string)?;
+    let stream = connect(server)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
 
-    let mut tls = connector.connect(server.hostname.as_str(), stream)?;
+    let tls = connector.connect(server.hostname.as_str(), stream);
+    metrics::tls_handshake(tls.is_ok());
+    let mut tls = tls?;
 
     // Write the serialized DNS request into the stream
     tls.write_all(msg)?;
@@ -50,3 +163,26 @@ pub fn relay_message(msg: &[u8], conf: &Config) -> Result<Vec<u8>> {
     // Return the response buffer to the caller
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(ip_address: &str) -> DnsServer {
+        DnsServer {
+            ip_address: ip_address.to_string(),
+            port: 853,
+            hostname: "dns.example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn upstream_addresses_work() {
+        let v4: SocketAddr = "192.0.2.53:853".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::53]:853".parse().unwrap();
+        assert_eq!(upstream_addresses(&server("192.0.2.53")).unwrap(), [v4]);
+        assert_eq!(upstream_addresses(&server("2001:db8::53")).unwrap(), [v6]);
+        assert_eq!(upstream_addresses(&server("[2001:db8::53]")).unwrap(), [v6]);
+        assert!(!upstream_addresses(&server("localhost")).unwrap().is_empty());
+    }
+}